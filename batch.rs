@@ -0,0 +1,165 @@
+use std::io::{File};
+use std::io::fs::{walk_dir};
+use std::path::{Path};
+use std::strbuf::StrBuf;
+
+use super::collection::{Levels};
+use super::sokoannotatedboard::{SokoAnnotatedBoard};
+use super::sokoboard::{SokoBoard};
+use super::solver::{Solver, Limits, Stats, Unsolvable, InvalidBoard, Unsupported, LimitReached, Cancelled};
+use super::solver::{TimeLimit, IterationLimit, NodeLimit};
+
+/// One line of the batch report.
+struct Row {
+  name: StrBuf,
+  status: &'static str,
+  length: Option<(uint, uint)>,
+  iterations: uint,
+  millis: u64,
+  largest_layer: uint,
+}
+
+/// Solves every level in every file below `dir` with `solver` and prints a
+/// summary table. When `csv` is given the report is also written there as CSV.
+pub fn run(dir: &Path, solver: &Solver, limits: &Limits, csv: Option<Path>) {
+  let mut paths: Vec<Path> = match walk_dir(dir) {
+    Ok(paths) => paths.filter(|p| p.is_file()).collect(),
+    Err(e) => {
+      println!("Cannot read {}: {}", dir.display(), e);
+      return;
+    }
+  };
+  paths.sort_by(|a, b| natural_key(a).cmp(&natural_key(b)));
+
+  println!("{:<24} {:<10} {:>8} {:>8} {:>10} {:>10} {:>12}",
+      "level", "status", "moves", "pushes", "layers", "ms", "layer nodes");
+  let mut rows = Vec::new();
+  for path in paths.iter() {
+    let contents = match File::open(path).read_to_str() {
+      Ok(contents) => contents,
+      Err(e) => {
+        println!("Cannot read {}: {}", path.display(), e);
+        continue;
+      }
+    };
+    let file = StrBuf::from_str(path.filename_str().unwrap_or("?"));
+    let levels: Vec<_> = Levels::new(contents.as_slice()).collect();
+    let single = levels.len() == 1;
+    for level in levels.move_iter() {
+      let row = match level {
+        Ok(level) => {
+          let name = if single { file.clone() } else { level_name(&file, level.index) };
+          solve_level(solver, name, level.board, limits)
+        }
+        Err(e) => {
+          println!("{}: {}", file, e);
+          Row {
+            name: if single { file.clone() } else { level_name(&file, e.index) },
+            status: "invalid",
+            length: None,
+            iterations: 0,
+            millis: 0,
+            largest_layer: 0,
+          }
+        }
+      };
+      print_row(&row);
+      rows.push(row);
+    }
+  }
+  print_summary(rows.as_slice());
+
+  match csv {
+    Some(csv) => {
+      match write_csv(&csv, rows.as_slice()) {
+        Ok(()) => println!("Report written to {}", csv.display()),
+        Err(e) => println!("Cannot write {}: {}", csv.display(), e),
+      }
+    }
+    None => {}
+  }
+}
+
+fn level_name(file: &StrBuf, index: uint) -> StrBuf {
+  StrBuf::from_owned_str(format!("{}#{}", file, index))
+}
+
+/// Solves `board` and checks the solution by replaying it; a solution that
+/// does not work is reported as wrong.
+fn solve_level(solver: &Solver, name: StrBuf, board: SokoBoard, limits: &Limits) -> Row {
+  let annotated = SokoAnnotatedBoard::fromSokoBoard(board.clone());
+  let (status, length, stats) = match solver.solve(&annotated, limits) {
+    Ok(solution) => match board.verify_strict(solution.lurd().as_slice()) {
+      Ok(true) => ("solved", Some((solution.steps, solution.pushes)), solution.stats),
+      Ok(false) => {
+        println!("{}: the solution does not solve the level", name);
+        ("wrong", Some((solution.steps, solution.pushes)), solution.stats)
+      }
+      Err(e) => {
+        println!("{}: the solution is not valid: {}", name, e);
+        ("wrong", Some((solution.steps, solution.pushes)), solution.stats)
+      }
+    },
+    Err(Unsolvable(stats)) => ("unsolvable", None, stats),
+    Err(InvalidBoard(reason)) => {
+      println!("{}: {}", name, reason);
+      ("invalid", None, Stats::new())
+    }
+    Err(Unsupported(reason)) => {
+      println!("{}: {}", name, reason);
+      ("bad search", None, Stats::new())
+    }
+    Err(LimitReached(TimeLimit, stats)) => ("timeout", None, stats),
+    Err(LimitReached(IterationLimit, stats)) => ("max iter", None, stats),
+    Err(LimitReached(NodeLimit, stats)) => ("max nodes", None, stats),
+    Err(Cancelled(stats)) => ("cancelled", None, stats),
+  };
+  Row {
+    name: name,
+    status: status,
+    length: length,
+    iterations: stats.iterations,
+    millis: stats.millis,
+    largest_layer: stats.largest_layer,
+  }
+}
+
+/// Orders `screen.2` before `screen.10`: the name is split into the text
+/// before its trailing number and the number itself.
+fn natural_key(path: &Path) -> (StrBuf, uint) {
+  let name = path.filename_str().unwrap_or("");
+  let digits = name.chars_rev().take_while(|c| c.is_digit()).count();
+  let (text, number) = (name.slice_to(name.len() - digits), name.slice_from(name.len() - digits));
+  (StrBuf::from_str(text), from_str(number).unwrap_or(0))
+}
+
+fn print_row(row: &Row) {
+  let (moves, pushes) = match row.length {
+    Some((moves, pushes)) => (moves.to_str(), pushes.to_str()),
+    None => ("-".to_owned(), "-".to_owned()),
+  };
+  println!("{:<24} {:<10} {:>8} {:>8} {:>10} {:>10} {:>12}",
+      row.name, row.status, moves, pushes, row.iterations, row.millis, row.largest_layer);
+}
+
+fn print_summary(rows: &[Row]) {
+  let count = |status: &str| rows.iter().filter(|row| row.status == status).count();
+  let millis = rows.iter().fold(0u64, |total, row| total + row.millis);
+  let limited = count("timeout") + count("max iter") + count("max nodes");
+  println!("{} levels: {} solved, {} unsolvable, {} over a limit, {} invalid, {} wrong in {} ms",
+      rows.len(), count("solved"), count("unsolvable"), limited, count("invalid"), count("wrong"), millis);
+}
+
+fn write_csv(path: &Path, rows: &[Row]) -> ::std::io::IoResult<()> {
+  let mut file = try!(File::create(path));
+  try!(file.write_line("level,status,moves,pushes,layers,ms,largest_layer"));
+  for row in rows.iter() {
+    let (moves, pushes) = match row.length {
+      Some((moves, pushes)) => (moves.to_str(), pushes.to_str()),
+      None => ("".to_owned(), "".to_owned()),
+    };
+    try!(file.write_line(format!("{},{},{},{},{},{},{}",
+        row.name, row.status, moves, pushes, row.iterations, row.millis, row.largest_layer).as_slice()));
+  }
+  Ok(())
+}
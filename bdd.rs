@@ -1,4 +1,10 @@
+use std::kinds::marker;
+use std::os;
+use std::sync::atomics::{AtomicBool, INIT_ATOMIC_BOOL, SeqCst};
+use libc::{c_void};
+
 use raw::{BDD};
+use raw::{raw_run, raw_init, raw_quit};
 use raw::{sylvan_low,sylvan_high,sylvan_ite};
 use raw::{sylvan_and,sylvan_xor,sylvan_or,sylvan_nand,
           sylvan_nor,sylvan_imp,sylvan_biimp,sylvan_diff,
@@ -7,179 +13,357 @@ use raw::{sylvan_not};
 use raw::{sylvan_ithvar};
 use raw::{sylvan_true, sylvan_false};
 use raw::{sylvan_relprods, sylvan_relprods_reversed};
+use raw::{sylvan_nodecount, sylvan_satcount};
+use raw::{sylvan_ref, sylvan_deref};
 
-pub struct Bdd(BDD);
+/// A reference to a node in sylvan's table. Every `Bdd` holds a reference
+/// that is released when it is dropped, so the node survives garbage
+/// collection for as long as the `Bdd` is alive. The lifetime is that of the
+/// session that made it, so a `Bdd` cannot outlive sylvan.
+pub struct Bdd<'a>(BDD, marker::CovariantLifetime<'a>);
 
-fn unpack(a: &Bdd, b: Bdd) -> (BDD,BDD) {
-  let (Bdd(a),Bdd(b)) = (*a,b);
+fn unpack(a: &Bdd, b: &Bdd) -> (BDD,BDD) {
+  let (&Bdd(a, _),&Bdd(b, _)) = (a,b);
   (a,b)
 }
 
-impl Eq for Bdd {
-  fn eq(&self, other: &Bdd) -> bool {
-    let (&Bdd(a),&Bdd(b)) = (self,other);
+/// Wraps a fresh result of a sylvan operation, taking a reference to it.
+fn wrap<'a>(a: BDD) -> Bdd<'a> {
+  unsafe {
+    Bdd(sylvan_ref(a), marker::CovariantLifetime)
+  }
+}
+
+impl<'a> Clone for Bdd<'a> {
+  fn clone(&self) -> Bdd<'a> {
+    let &Bdd(a, _) = self;
+    wrap(a)
+  }
+}
+
+#[unsafe_destructor]
+impl<'a> Drop for Bdd<'a> {
+  fn drop(&mut self) {
+    let &Bdd(a, _) = self;
+    unsafe {
+      sylvan_deref(a);
+    }
+  }
+}
+
+impl<'a> Eq for Bdd<'a> {
+  fn eq(&self, other: &Bdd<'a>) -> bool {
+    let (&Bdd(a, _),&Bdd(b, _)) = (self,other);
     a == b
   }
 }
 
-impl Not<Bdd> for Bdd {
-  fn not(&self) -> Bdd {
-    let &Bdd(a) = self;
+impl<'a> Not<Bdd<'a>> for Bdd<'a> {
+  fn not(&self) -> Bdd<'a> {
+    let &Bdd(a, _) = self;
     unsafe {
-      Bdd(sylvan_not(a))
+      wrap(sylvan_not(a))
     }
   }
 }
 
-impl BitAnd<Bdd,Bdd> for Bdd {
-  fn bitand(&self, rhs: &Bdd) -> Bdd {
-    let (a,b) = unpack(self,*rhs);
+impl<'a> BitAnd<Bdd<'a>,Bdd<'a>> for Bdd<'a> {
+  fn bitand(&self, rhs: &Bdd<'a>) -> Bdd<'a> {
+    let (a,b) = unpack(self,rhs);
     unsafe {
-      Bdd(sylvan_and(a,b))
+      wrap(sylvan_and(a,b))
     }
   }
 }
 
-impl BitOr<Bdd,Bdd> for Bdd {
-  fn bitor(&self, rhs: &Bdd) -> Bdd {
-    let (a,b) = unpack(self,*rhs);
+impl<'a> BitOr<Bdd<'a>,Bdd<'a>> for Bdd<'a> {
+  fn bitor(&self, rhs: &Bdd<'a>) -> Bdd<'a> {
+    let (a,b) = unpack(self,rhs);
     unsafe {
-      Bdd(sylvan_or(a,b))
+      wrap(sylvan_or(a,b))
     }
   }
 }
 
-impl BitXor<Bdd,Bdd> for Bdd {
-  fn bitxor(&self, rhs: &Bdd) -> Bdd {
-    let (a,b) = unpack(self,*rhs);
+impl<'a> BitXor<Bdd<'a>,Bdd<'a>> for Bdd<'a> {
+  fn bitxor(&self, rhs: &Bdd<'a>) -> Bdd<'a> {
+    let (a,b) = unpack(self,rhs);
     unsafe {
-      Bdd(sylvan_xor(a,b))
+      wrap(sylvan_xor(a,b))
     }
   }
 }
 
-#[allow(dead_code)]
-impl Bdd {
-  pub fn fromId(id: u32) -> Bdd {
+/// How to size lace and sylvan.
+/// `workers` is the number of lace worker threads, by default one per core
+/// `deque_size` is the size of each worker's task deque
+/// `table_size` is the log2 of the number of entries in the node table
+/// `cache_size` is the log2 of the number of entries in the operation cache
+/// `granularity` determines how many levels of recursion share a cache entry
+#[derive(Clone)]
+pub struct Config {
+  pub workers: uint,
+  pub deque_size: uint,
+  pub table_size: uint,
+  pub cache_size: uint,
+  pub granularity: uint,
+}
+
+impl Config {
+  pub fn new() -> Config {
+    Config {
+      workers: os::num_cpus(),
+      deque_size: 100000,
+      table_size: 26,
+      cache_size: 22,
+      granularity: 2,
+    }
+  }
+}
+
+static mut ACTIVE: AtomicBool = INIT_ATOMIC_BOOL;
+
+/// A running instance of lace and sylvan. At most one session exists at any
+/// time, and it only exists while `Sylvan::run` runs: sylvan may only be used
+/// from a lace task, so the session is handed to a closure that runs as
+/// lace's root task. Every `Bdd` is created through the session and borrows
+/// it, so it is always dropped before sylvan shuts down.
+pub struct Sylvan {
+  config: Config,
+  nosend: marker::NoSend,
+}
+
+/// What `root_task` needs to start sylvan and run the caller's closure.
+struct Root<'a> {
+  config: Config,
+  body: &'a mut |&Sylvan|,
+}
+
+extern "C" fn root_task(_worker: *c_void, _task: *c_void, arg: *mut c_void) {
+  let root = unsafe { &mut *(arg as *mut Root) };
+  unsafe {
+    raw_init(root.config.table_size, root.config.cache_size, root.config.granularity);
+  }
+  {
+    let syl = Sylvan {
+      config: root.config.clone(),
+      nosend: marker::NoSend,
+    };
+    (*root.body)(&syl);
+  }
+  unsafe {
+    raw_quit();
+  }
+}
+
+impl Sylvan {
+  /// Starts lace and sylvan, runs `f` with the session as lace's root task,
+  /// shuts both down and returns what `f` returned. Returns `None` without
+  /// calling `f` when a session is already running. The calling thread is
+  /// lace's first worker and runs `f` itself. `f` must not fail, as a
+  /// failure cannot unwind through lace.
+  pub fn run<T>(config: Config, f: |&Sylvan| -> T) -> Option<T> {
+    unsafe {
+      if ACTIVE.swap(true, SeqCst) {
+        return None;
+      }
+    }
+    let mut result = None;
+    {
+      let mut body: |&Sylvan| = |syl| result = Some(f(syl));
+      let mut root = Root {
+        config: config.clone(),
+        body: &mut body,
+      };
+      unsafe {
+        raw_run(config.workers, config.deque_size, root_task, &mut root as *mut Root as *mut c_void);
+      }
+    }
+    unsafe {
+      ACTIVE.store(false, SeqCst);
+    }
+    result
+  }
+
+  pub fn config<'a>(&'a self) -> &'a Config {
+    &self.config
+  }
+
+  pub fn fromId<'a>(&'a self, id: u32) -> Bdd<'a> {
     unsafe {
-      Bdd(sylvan_ithvar(id))
+      wrap(sylvan_ithvar(id))
     }
   }
 
-  pub fn bddTrue() -> Bdd {
-    Bdd(sylvan_true)
+  pub fn bddTrue<'a>(&'a self) -> Bdd<'a> {
+    wrap(sylvan_true)
   }
 
-  pub fn bddFalse() -> Bdd {
-    Bdd(sylvan_false)
+  pub fn bddFalse<'a>(&'a self) -> Bdd<'a> {
+    wrap(sylvan_false)
   }
+}
 
-  pub fn low(&self) -> Bdd {
-    let &Bdd(a) = self;
+#[allow(dead_code)]
+impl<'a> Bdd<'a> {
+  pub fn low(&self) -> Bdd<'a> {
+    let &Bdd(a, _) = self;
     unsafe {
-      Bdd(sylvan_low(a))
+      wrap(sylvan_low(a))
     }
   }
 
-  pub fn high(&self) -> Bdd {
-    let &Bdd(a) = self;
+  pub fn high(&self) -> Bdd<'a> {
+    let &Bdd(a, _) = self;
     unsafe {
-      Bdd(sylvan_high(a))
+      wrap(sylvan_high(a))
     }
   }
 
-  pub fn not(&self) -> Bdd {
-    let &Bdd(a) = self;
+  pub fn not(&self) -> Bdd<'a> {
+    let &Bdd(a, _) = self;
     unsafe {
-      Bdd(sylvan_not(a))
+      wrap(sylvan_not(a))
     }
   }
 
-  pub fn ite(&self, bdd_if: Bdd, bdd_else: Bdd) -> Bdd {
-    let (&Bdd(a),Bdd(b),Bdd(c)) = (self,bdd_if,bdd_else);
+  pub fn ite(&self, bdd_if: &Bdd<'a>, bdd_else: &Bdd<'a>) -> Bdd<'a> {
+    let (&Bdd(a, _),&Bdd(b, _),&Bdd(c, _)) = (self,bdd_if,bdd_else);
     unsafe {
-      Bdd(sylvan_ite(a,b,c))
+      wrap(sylvan_ite(a,b,c))
     }
   }
 
-  pub fn and(&self, other: Bdd) -> Bdd {
+  pub fn and(&self, other: &Bdd<'a>) -> Bdd<'a> {
     let (a,b) = unpack(self,other);
     unsafe {
-      Bdd(sylvan_and(a,b))
+      wrap(sylvan_and(a,b))
     }
   }
-  pub fn xor(&self, other: Bdd) -> Bdd {
+  pub fn xor(&self, other: &Bdd<'a>) -> Bdd<'a> {
     let (a,b) = unpack(self,other);
     unsafe {
-      Bdd(sylvan_xor(a,b))
+      wrap(sylvan_xor(a,b))
     }
   }
-  pub fn or(&self, other: Bdd) -> Bdd {
+  pub fn or(&self, other: &Bdd<'a>) -> Bdd<'a> {
     let (a,b) = unpack(self,other);
     unsafe {
-      Bdd(sylvan_or(a,b))
+      wrap(sylvan_or(a,b))
     }
   }
-  pub fn nand(&self, other: Bdd) -> Bdd {
+  pub fn nand(&self, other: &Bdd<'a>) -> Bdd<'a> {
     let (a,b) = unpack(self,other);
     unsafe {
-      Bdd(sylvan_nand(a,b))
+      wrap(sylvan_nand(a,b))
     }
   }
-  pub fn nor(&self, other: Bdd) -> Bdd {
+  pub fn nor(&self, other: &Bdd<'a>) -> Bdd<'a> {
     let (a,b) = unpack(self,other);
     unsafe {
-      Bdd(sylvan_nor(a,b))
+      wrap(sylvan_nor(a,b))
     }
   }
-  pub fn imp(&self, other: Bdd) -> Bdd {
+  pub fn imp(&self, other: &Bdd<'a>) -> Bdd<'a> {
     let (a,b) = unpack(self,other);
     unsafe {
-      Bdd(sylvan_imp(a,b))
+      wrap(sylvan_imp(a,b))
     }
   }
-  pub fn biimp(&self, other: Bdd) -> Bdd {
+  pub fn biimp(&self, other: &Bdd<'a>) -> Bdd<'a> {
     let (a,b) = unpack(self,other);
     unsafe {
-      Bdd(sylvan_biimp(a,b))
+      wrap(sylvan_biimp(a,b))
     }
   }
-  pub fn diff(&self, other: Bdd) -> Bdd {
+  pub fn diff(&self, other: &Bdd<'a>) -> Bdd<'a> {
     let (a,b) = unpack(self,other);
     unsafe {
-      Bdd(sylvan_diff(a,b))
+      wrap(sylvan_diff(a,b))
     }
   }
-  pub fn less(&self, other: Bdd) -> Bdd {
+  pub fn less(&self, other: &Bdd<'a>) -> Bdd<'a> {
     let (a,b) = unpack(self,other);
     unsafe {
-      Bdd(sylvan_less(a,b))
+      wrap(sylvan_less(a,b))
     }
   }
-  pub fn invimp(&self, other: Bdd) -> Bdd {
+  pub fn invimp(&self, other: &Bdd<'a>) -> Bdd<'a> {
     let (a,b) = unpack(self,other);
     unsafe {
-      Bdd(sylvan_invimp(a,b))
+      wrap(sylvan_invimp(a,b))
     }
   }
 
-  pub fn relprods(&self, transitions: Bdd, allvars: Bdd) -> Bdd {
+  pub fn relprods(&self, transitions: &Bdd<'a>, allvars: &Bdd<'a>) -> Bdd<'a> {
     let (current,trans) = unpack(self, transitions);
-    let Bdd(vars) = allvars;
+    let &Bdd(vars, _) = allvars;
     unsafe {
-      Bdd( sylvan_relprods(current, trans, vars) )
+      wrap( sylvan_relprods(current, trans, vars) )
     }
   }
 
-  pub fn relprods_reversed(&self, transitions: Bdd, allvars: Bdd) -> Bdd {
+  pub fn relprods_reversed(&self, transitions: &Bdd<'a>, allvars: &Bdd<'a>) -> Bdd<'a> {
     let (current,trans) = unpack(self, transitions);
-    let Bdd(vars) = allvars;
+    let &Bdd(vars, _) = allvars;
+    unsafe {
+      wrap( sylvan_relprods_reversed(current,trans,vars) )
+    }
+  }
+
+  /// The number of nodes in this bdd.
+  pub fn node_count(&self) -> uint {
+    let &Bdd(a, _) = self;
     unsafe {
-      Bdd( sylvan_relprods_reversed(current,trans,vars) )
+      sylvan_nodecount(a) as uint
     }
   }
 
-  pub fn relprods_equalize(&self, transitions: Bdd, allvars: Bdd, equalizer: Bdd) -> Bdd {
+  /// The number of assignments to the variables in `vars` that satisfy this
+  /// bdd.
+  pub fn sat_count(&self, vars: &Bdd<'a>) -> f64 {
+    let (a,v) = unpack(self, vars);
+    unsafe {
+      sylvan_satcount(a, v)
+    }
+  }
+
+  pub fn relprods_equalize(&self, transitions: &Bdd<'a>, allvars: &Bdd<'a>, equalizer: &Bdd<'a>) -> Bdd<'a> {
     self.relprods(transitions,allvars).relprods_reversed(equalizer,allvars)
   }
 }
+
+#[cfg(test)]
+mod test {
+  use super::{Sylvan, Config};
+
+  fn config(workers: uint) -> Config {
+    let mut config = Config::new();
+    config.workers = workers;
+    config.table_size = 16;
+    config.cache_size = 14;
+    config
+  }
+
+  #[test]
+  fn relprod_with_several_workers() {
+    // Variable 0 is the current state and 1 the next; the relation flips it.
+    let image = Sylvan::run(config(2), |syl| {
+      let x = syl.fromId(0);
+      let next = syl.fromId(1);
+      let vars = x | next;
+      let flip = x.biimp(&next.not());
+      let from_false = x.not().relprods(&flip, &vars);
+      let from_both = syl.bddTrue().relprods(&flip, &vars);
+      (from_false == x, from_both == syl.bddTrue())
+    });
+    assert_eq!(image, Some((true, true)));
+  }
+
+  #[test]
+  fn sessions_run_one_at_a_time() {
+    let nested = Sylvan::run(config(2), |_| Sylvan::run(config(1), |_| ()));
+    assert_eq!(nested, Some(None));
+    assert!(Sylvan::run(config(1), |_| ()).is_some());
+  }
+}
@@ -0,0 +1,272 @@
+use std::fmt;
+use std::strbuf::StrBuf;
+
+use super::sokoboard::{SokoBoard, ParseError};
+
+/// The descriptive headers that may accompany a level in a collection.
+#[derive(Clone)]
+pub struct Metadata {
+  pub title: Option<StrBuf>,
+  pub author: Option<StrBuf>,
+  pub comment: Option<StrBuf>,
+}
+
+impl Metadata {
+  pub fn new() -> Metadata {
+    Metadata {
+      title: None,
+      author: None,
+      comment: None,
+    }
+  }
+}
+
+/// A single level read from a collection. `index` is the 1-based position of
+/// the level in the collection, `line` the 1-based line its board starts on.
+pub struct Level {
+  pub index: uint,
+  pub line: uint,
+  pub metadata: Metadata,
+  pub board: SokoBoard,
+}
+
+impl Level {
+  /// The title of the level, or "Level <index>" when it has none.
+  pub fn name(&self) -> StrBuf {
+    level_name(self.index, &self.metadata)
+  }
+}
+
+/// A level in a collection that could not be parsed. The line numbers in
+/// `error` are relative to the start of the collection.
+pub struct LevelError {
+  pub index: uint,
+  pub metadata: Metadata,
+  pub error: ParseError,
+}
+
+impl LevelError {
+  pub fn name(&self) -> StrBuf {
+    level_name(self.index, &self.metadata)
+  }
+}
+
+impl fmt::Show for LevelError {
+  fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+    write!(fmt.buf, "{}: {}", self.name(), self.error)
+  }
+}
+
+fn level_name(index: uint, metadata: &Metadata) -> StrBuf {
+  match metadata.title {
+    Some(ref title) => title.clone(),
+    None => StrBuf::from_owned_str(format!("Level {}", index)),
+  }
+}
+
+/// Iterates over the levels of a collection file. Levels are separated by
+/// blank lines and may be preceded or followed by `Title:`, `Author:` and
+/// `Comment:` headers. A multi-line comment is written as an empty
+/// `Comment:` header and runs until `Comment-End:`. Any other text next to a
+/// board is taken as its title, or appended to its comment when it already
+/// has one. A board starts at the first line with a wall that is not a
+/// header, and runs until a blank line or a header; whether its lines are
+/// valid rows is up to `SokoBoard::parse`.
+pub struct Levels<'a> {
+  lines: Vec<&'a str>,
+  pos: uint,
+  index: uint,
+}
+
+impl<'a> Levels<'a> {
+  pub fn new(s: &'a str) -> Levels<'a> {
+    Levels {
+      lines: s.lines_any().collect(),
+      pos: 0,
+      index: 0,
+    }
+  }
+}
+
+impl<'a> Iterator<Result<Level, LevelError>> for Levels<'a> {
+  fn next(&mut self) -> Option<Result<Level, LevelError>> {
+    let mut metadata = Metadata::new();
+    let mut in_comment = false;
+
+    // Headers before the board.
+    loop {
+      if self.pos >= self.lines.len() {
+        return None;
+      }
+      let line = self.lines[self.pos];
+      if !in_comment && starts_board(line) {
+        break;
+      }
+      add_metadata(&mut metadata, line, &mut in_comment);
+      self.pos += 1;
+    }
+
+    let start = self.pos;
+    while self.pos < self.lines.len() && continues_board(self.lines[self.pos]) {
+      self.pos += 1;
+    }
+    let text = self.lines.slice(start, self.pos).connect("\n");
+
+    // Headers directly following the board, up to the next blank line.
+    while self.pos < self.lines.len() {
+      let line = self.lines[self.pos];
+      if !in_comment && (starts_board(line) || line.trim().is_empty()) {
+        break;
+      }
+      add_metadata(&mut metadata, line, &mut in_comment);
+      self.pos += 1;
+    }
+
+    self.index += 1;
+    Some(match SokoBoard::parse(text.as_slice()) {
+      Ok(board) => Ok(Level {
+        index: self.index,
+        line: start + 1,
+        metadata: metadata,
+        board: board,
+      }),
+      Err(mut error) => {
+        if error.line > 0 {
+          error.line += start;
+        }
+        Err(LevelError {
+          index: self.index,
+          metadata: metadata,
+          error: error,
+        })
+      }
+    })
+  }
+}
+
+/// The first row of a board is the first line with a wall in it that is not
+/// a header.
+fn starts_board(line: &str) -> bool {
+  line.contains_char('#') && !is_header(line)
+}
+
+/// Once a board has started, every following line up to a blank line or a
+/// header is one of its rows.
+fn continues_board(line: &str) -> bool {
+  !line.trim().is_empty() && !is_header(line)
+}
+
+fn is_header(line: &str) -> bool {
+  match line.find(':') {
+    Some(ix) => match line.slice_to(ix).trim().to_ascii_lower().as_slice() {
+      "title" | "author" | "comment" | "comment-end" | "comment_end" => true,
+      _ => false,
+    },
+    None => false,
+  }
+}
+
+fn add_metadata(metadata: &mut Metadata, line: &str, in_comment: &mut bool) {
+  let line = line.trim();
+  let (key, value) = match line.find(':') {
+    Some(ix) => (line.slice_to(ix).trim().to_ascii_lower(), line.slice_from(ix+1).trim()),
+    None => (StrBuf::new(), line),
+  };
+
+  if *in_comment {
+    if key.as_slice() == "comment-end" || key.as_slice() == "comment_end" {
+      *in_comment = false;
+    } else {
+      append_comment(metadata, line);
+    }
+    return;
+  }
+
+  match key.as_slice() {
+    "title" => metadata.title = Some(StrBuf::from_str(value)),
+    "author" => metadata.author = Some(StrBuf::from_str(value)),
+    "comment" => {
+      if value.is_empty() {
+        *in_comment = true;
+      } else {
+        append_comment(metadata, value);
+      }
+    }
+    _ => {
+      let text = line.trim_left_chars(';').trim();
+      if text.is_empty() {
+        return;
+      }
+      if metadata.title.is_none() {
+        metadata.title = Some(StrBuf::from_str(text));
+      } else {
+        append_comment(metadata, text);
+      }
+    }
+  }
+}
+
+fn append_comment(metadata: &mut Metadata, text: &str) {
+  match metadata.comment {
+    Some(ref mut comment) => {
+      comment.push_char('\n');
+      comment.push_str(text);
+    }
+    None => metadata.comment = Some(StrBuf::from_str(text)),
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::{Levels, Level, LevelError};
+  use sokoboard::{UnknownGlyph};
+
+  fn level<'a>(result: &'a Result<Level, LevelError>) -> &'a Level {
+    match *result {
+      Ok(ref level) => level,
+      Err(ref error) => panic!("{}", error),
+    }
+  }
+
+  fn error<'a>(result: &'a Result<Level, LevelError>) -> &'a LevelError {
+    match *result {
+      Ok(ref level) => panic!("{} should not parse", level.name()),
+      Err(ref error) => error,
+    }
+  }
+
+  #[test]
+  fn reads_headers_and_boards() {
+    let text = "Title: First\n#####\n#@$.#\n#####\nAuthor: Someone\n\n; Second\n#####\n#.$@#\n#####\n";
+    let levels: Vec<_> = Levels::new(text).collect();
+    assert_eq!(levels.len(), 2);
+    let first = level(levels.get(0));
+    assert_eq!(first.name().as_slice(), "First");
+    assert_eq!(first.metadata.author.as_ref().map(|a| a.as_slice()), Some("Someone"));
+    assert_eq!(first.line, 2);
+    let second = level(levels.get(1));
+    assert_eq!(second.name().as_slice(), "Second");
+    assert_eq!(second.index, 2);
+  }
+
+  #[test]
+  fn a_title_may_contain_a_wall() {
+    let text = "Title: #1\n#####\n#@$.#\n#####\n";
+    let levels: Vec<_> = Levels::new(text).collect();
+    assert_eq!(levels.len(), 1);
+    let level = level(levels.get(0));
+    assert_eq!(level.name().as_slice(), "#1");
+    assert_eq!(level.line, 2);
+  }
+
+  #[test]
+  fn a_bad_glyph_is_reported_where_it_is() {
+    let text = "Title: Tabbed\n#####\n#@$.#\n#\t  #\n#####\n";
+    let levels: Vec<_> = Levels::new(text).collect();
+    assert_eq!(levels.len(), 1);
+    let error = error(levels.get(0));
+    assert_eq!(error.name().as_slice(), "Tabbed");
+    assert!(error.error.reason == UnknownGlyph);
+    assert_eq!((error.error.line, error.error.column), (4, 2));
+  }
+}
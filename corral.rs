@@ -0,0 +1,109 @@
+use super::grid::{Grid, DIRECTIONS};
+
+/// An area the man cannot get into, fenced off by blocks. `inside` marks its
+/// squares, including the blocks, `boundary` the blocks that touch squares
+/// the man can reach, and `pushes` is the number of pushes into it the man
+/// can make right now.
+pub struct Corral {
+  pub inside: Vec<bool>,
+  pub boundary: Vec<bool>,
+  pub pushes: uint,
+}
+
+impl Corral {
+  /// `true` if pushing the block on `from` to `to` moves a boundary block
+  /// into the corral.
+  pub fn admits(&self, from: uint, to: uint) -> bool {
+    self.boundary[from] && self.inside[to]
+  }
+}
+
+/// Finds a PI-corral in the position with blocks on `occupied` and the man
+/// able to reach `reach`: a corral every push of whose boundary blocks goes
+/// into it (I), where the man can get to every one of those pushes now (P),
+/// and that still holds a block off a goal or a goal without a block. Such a
+/// corral has to be opened up some time, and nothing outside it helps to do
+/// so, so only the pushes into it need to be tried. Of all PI-corrals the
+/// one with the fewest pushes is returned.
+pub fn pi_corral(grid: &Grid, occupied: &[bool], reach: &[bool]) -> Option<Corral> {
+  let mut seen = Vec::from_elem(grid.cells(), false);
+  let mut best: Option<Corral> = None;
+  for start in range(0, grid.cells()) {
+    if grid.isWall(start) || reach[start] || occupied[start] || seen[start] {
+      continue;
+    }
+    let mut inside = Vec::from_elem(grid.cells(), false);
+    let mut todo = vec!(start);
+    *inside.get_mut(start) = true;
+    while !todo.is_empty() {
+      let cell = todo.pop().unwrap();
+      *seen.get_mut(cell) = true;
+      for &dir in DIRECTIONS.iter() {
+        match grid.neighbour(cell, dir) {
+          Some(next) if !inside[next] && !grid.isWall(next) && !reach[next] => {
+            *inside.get_mut(next) = true;
+            todo.push(next);
+          }
+          _ => {}
+        }
+      }
+    }
+    match examine(grid, occupied, reach, inside) {
+      Some(corral) => {
+        if best.as_ref().map_or(true, |best| corral.pushes < best.pushes) {
+          best = Some(corral);
+        }
+      }
+      None => {}
+    }
+  }
+  best
+}
+
+/// Checks whether the area marked by `inside` is a PI-corral that matters.
+fn examine(grid: &Grid, occupied: &[bool], reach: &[bool], inside: Vec<bool>) -> Option<Corral> {
+  let mut boundary = Vec::from_elem(grid.cells(), false);
+  let mut pushes = 0u;
+  let mut unfinished = false;
+  for cell in range(0, grid.cells()) {
+    if !inside[cell] {
+      continue;
+    }
+    if occupied[cell] != grid.isGoal(cell) {
+      unfinished = true;
+    }
+    if !occupied[cell] || !DIRECTIONS.iter().any(|&dir| grid.neighbour(cell, dir).map_or(false, |next| reach[next])) {
+      continue;
+    }
+    *boundary.get_mut(cell) = true;
+    for &dir in DIRECTIONS.iter() {
+      let (from, to) = match (grid.neighbour(cell, dir.opposite()), grid.neighbour(cell, dir)) {
+        (Some(from), Some(to)) => (from, to),
+        _ => continue,
+      };
+      // Pushes made from inside the corral have to wait until it is opened.
+      if grid.isWall(to) || grid.isDead(to) || occupied[to] || grid.isWall(from) || occupied[from] || inside[from] {
+        continue;
+      }
+      if !inside[to] {
+        if reach[from] {
+          // The block can be pushed along the outside of the corral.
+          return None;
+        }
+      } else if reach[from] {
+        pushes += 1;
+      } else {
+        // A push into the corral the man cannot get to yet.
+        return None;
+      }
+    }
+  }
+  if !unfinished || pushes == 0 {
+    return None;
+  }
+  Some(Corral {
+    inside: inside,
+    boundary: boundary,
+    pushes: pushes,
+  })
+}
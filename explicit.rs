@@ -0,0 +1,252 @@
+use collections::HashMap;
+use collections::priority_queue::PriorityQueue;
+use time::{precise_time_ns};
+
+use super::corral::{pi_corral};
+use super::grid::{Grid, DIRECTIONS};
+use super::heuristic::{Heuristic};
+use super::patterns::{Patterns};
+use super::sokoannotatedboard::{SokoAnnotatedBoard};
+use super::sokoboard::{Direction, Move};
+use super::solver::{Solution, Stats, SolveError, Unsolvable, Limits, Solver, check_limits};
+use super::solver::{Progress, LayerReport};
+
+/// A position: the squares of the blocks in ascending order, and the man
+/// normalized to the lowest-numbered square of the region he can reach.
+#[derive(Eq, Hash, Clone)]
+struct State {
+  boxes: Vec<uint>,
+  man: uint,
+}
+
+/// A position found by the search. `push` is the block square and direction
+/// of the push that led here from `parent`.
+struct Node {
+  state: State,
+  parent: Option<uint>,
+  push: Option<(uint, Direction)>,
+  pushes: uint,
+}
+
+/// An entry in the open list. The queue pops its greatest element, so the
+/// ordering is reversed: lowest estimate first, and among equal estimates
+/// the node with the most pushes so far.
+#[derive(Eq)]
+struct Open {
+  estimate: uint,
+  pushes: uint,
+  node: uint,
+}
+
+impl Ord for Open {
+  fn lt(&self, other: &Open) -> bool {
+    self.estimate > other.estimate
+        || (self.estimate == other.estimate && self.pushes < other.pushes)
+  }
+}
+
+/// The lowest-numbered square reachable by a man standing on `man`.
+fn normalize(grid: &Grid, occupied: &[bool], man: uint) -> uint {
+  let reach = grid.reach(occupied, man);
+  range(0, grid.cells()).find(|&cell| reach[cell]).unwrap()
+}
+
+fn solved(grid: &Grid, boxes: &[uint]) -> bool {
+  boxes.iter().all(|&b| grid.isGoal(b))
+}
+
+/// Replays the pushes leading to `node`, letting the man walk to each one.
+fn reconstruct_path(grid: &Grid, nodes: &[Node], node: uint) -> Vec<Move> {
+  let mut pushes = Vec::new();
+  let mut current = node;
+  loop {
+    match nodes[current].push {
+      Some(push) => pushes.push(push),
+      None => break,
+    }
+    current = nodes[current].parent.unwrap();
+  }
+  pushes.reverse();
+
+  let mut path = Vec::new();
+  let mut occupied = grid.occupancy(grid.initial_boxes());
+  let mut man = grid.initial_man();
+  for &(b, dir) in pushes.iter() {
+    let from = grid.neighbour(b, dir.opposite()).unwrap();
+    let walk = grid.walk(occupied.as_slice(), man, from).expect("Replaying error");
+    path.extend(walk.move_iter().map(|step| Move { direction: step, push: false }));
+    path.push(Move { direction: dir, push: true });
+    *occupied.get_mut(b) = false;
+    *occupied.get_mut(grid.neighbour(b, dir).unwrap()) = true;
+    man = b;
+  }
+  path
+}
+
+/// Solves the puzzle with an A* search over individual positions, using the
+/// number of pushes as the cost, so the solution has as few pushes as
+/// possible. In the statistics `iterations` counts the positions expanded
+/// and `largest_layer` the positions stored. Whenever the estimate of the
+/// positions being expanded goes up, `progress` is told the new estimate as
+/// the layer, with the positions expanded and stored so far. Pushes that
+/// complete one of the deadlock `patterns` are never made, and where a
+/// PI-corral is found only the pushes into it are tried.
+pub fn solve_explicit(soko: &SokoAnnotatedBoard, patterns: &Patterns, limits: &Limits, progress: &mut Progress)
+    -> Result<Solution, SolveError> {
+  let start = precise_time_ns();
+  try!(soko.validate());
+  let grid = Grid::new(soko);
+  let heuristic = Heuristic::new(&grid);
+  let mut stats = Stats::new();
+  let mut fixed = Vec::from_elem(grid.cells(), false);
+
+  let occupied = grid.occupancy(grid.initial_boxes());
+  let initial = State {
+    boxes: Vec::from_slice(grid.initial_boxes()),
+    man: normalize(&grid, occupied.as_slice(), grid.initial_man()),
+  };
+  let initial_estimate = match heuristic.lower_bound(initial.boxes.as_slice()) {
+    Some(n) => n,
+    None => return Err(Unsolvable(stats)),
+  };
+
+  let mut nodes = vec!(Node { state: initial.clone(), parent: None, push: None, pushes: 0 });
+  let mut best = HashMap::new();
+  best.insert(initial, 0u);
+  let mut open = PriorityQueue::new();
+  open.push(Open { estimate: initial_estimate, pushes: 0, node: 0 });
+  let mut bound = initial_estimate;
+
+  loop {
+    let entry = match open.maybe_pop() {
+      Some(entry) => entry,
+      None => return Err(Unsolvable(stats)),
+    };
+    let (state, pushes) = {
+      let node = &nodes[entry.node];
+      (node.state.clone(), node.pushes)
+    };
+    if best.find(&state).map_or(false, |&known| known < pushes) {
+      continue;
+    }
+    if solved(&grid, state.boxes.as_slice()) {
+      let path = reconstruct_path(&grid, nodes.as_slice(), entry.node);
+      stats.millis = (precise_time_ns() - start) / 1000000;
+      return Ok(Solution::new(path, stats));
+    }
+    if entry.estimate > bound {
+      bound = entry.estimate;
+      progress.layer(&LayerReport {
+        layer: bound,
+        states: stats.iterations as f64,
+        nodes: nodes.len(),
+        millis: (precise_time_ns() - start) / 1000000,
+      });
+    }
+    try!(check_limits(start, limits, &mut stats));
+    stats.iterations += 1;
+
+    let occupied = grid.occupancy(state.boxes.as_slice());
+    let reach = grid.reach(occupied.as_slice(), state.man);
+    let corral = pi_corral(&grid, occupied.as_slice(), reach.as_slice());
+    if corral.is_some() {
+      stats.corrals += 1;
+    }
+    for (i, &b) in state.boxes.iter().enumerate() {
+      for &dir in DIRECTIONS.iter() {
+        let (from, to) = match (grid.neighbour(b, dir.opposite()), grid.neighbour(b, dir)) {
+          (Some(from), Some(to)) => (from, to),
+          _ => continue,
+        };
+        if !reach[from] || grid.isWall(to) || grid.isDead(to) || occupied[to] {
+          continue;
+        }
+        if corral.as_ref().map_or(false, |corral| !corral.admits(b, to)) {
+          stats.pruned += 1;
+          continue;
+        }
+        let mut boxes = state.boxes.clone();
+        *boxes.get_mut(i) = to;
+        boxes.sort();
+        let mut next_occupied = occupied.clone();
+        *next_occupied.get_mut(b) = false;
+        *next_occupied.get_mut(to) = true;
+        let next = State {
+          man: normalize(&grid, next_occupied.as_slice(), b),
+          boxes: boxes,
+        };
+        if best.find(&next).map_or(false, |&known| known <= pushes + 1) {
+          continue;
+        }
+        if grid.isFreezeDeadlock(next_occupied.as_slice(), fixed.as_mut_slice(), to)
+            || patterns.isDeadlock(&grid, next_occupied.as_slice(), to) {
+          continue;
+        }
+        let remaining = match heuristic.lower_bound(next.boxes.as_slice()) {
+          Some(n) => n,
+          None => continue,
+        };
+        best.insert(next.clone(), pushes + 1);
+        nodes.push(Node { state: next, parent: Some(entry.node), push: Some((b, dir)), pushes: pushes + 1 });
+        open.push(Open { estimate: pushes + 1 + remaining, pushes: pushes + 1, node: nodes.len() - 1 });
+      }
+    }
+    stats.largest_layer = nodes.len();
+  }
+}
+
+/// The explicit search as a `Solver`.
+pub struct ExplicitSolver<'a> {
+  patterns: &'a Patterns,
+}
+
+impl<'a> ExplicitSolver<'a> {
+  pub fn new(patterns: &'a Patterns) -> ExplicitSolver<'a> {
+    ExplicitSolver {
+      patterns: patterns,
+    }
+  }
+}
+
+impl<'a> Solver for ExplicitSolver<'a> {
+  fn solve_with_progress(&self, board: &SokoAnnotatedBoard, limits: &Limits, progress: &mut Progress)
+      -> Result<Solution, SolveError> {
+    solve_explicit(board, self.patterns, limits, progress)
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use patterns::{Patterns};
+  use sokoannotatedboard::{SokoAnnotatedBoard};
+  use sokoboard::{SokoBoard};
+  use solver::{Solver, Limits};
+  use super::{ExplicitSolver};
+
+  /// Solves `board` and checks the plan, returning its moves and pushes.
+  fn solve(board: &str) -> (uint, uint) {
+    let parsed = SokoBoard::parse(board).unwrap();
+    let patterns = Patterns::builtin().unwrap();
+    let solver = ExplicitSolver::new(&patterns);
+    match solver.solve(&SokoAnnotatedBoard::fromSokoBoard(parsed.clone()), &Limits::new()) {
+      Ok(solution) => {
+        assert!(parsed.verify_strict(solution.lurd().as_slice()) == Ok(true));
+        (solution.steps, solution.pushes)
+      }
+      Err(e) => panic!("{}", e),
+    }
+  }
+
+  #[test]
+  fn solves_a_corridor() {
+    assert_eq!(solve("#######\n#@ $ .#\n#######"), (3, 2));
+  }
+
+  #[test]
+  fn solves_with_as_few_pushes_as_possible() {
+    let (_, pushes) = solve("#######\n#  @  #\n# $$$ #\n#     #\n###-###\n#     #\n#.   .#\n###-###\n  #.#\n  ###");
+    // One block goes six squares down into the inner room; the other two
+    // each go one square sideways, four down and two sideways.
+    assert_eq!(pushes, 20);
+  }
+}
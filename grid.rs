@@ -0,0 +1,247 @@
+use super::sokoannotatedboard::{SokoAnnotatedBoard};
+use super::sokoboard::{Direction, Up, Down, Left, Right};
+
+pub static DIRECTIONS: [Direction, ..4] = [Up, Down, Left, Right];
+
+/// The static part of a puzzle as flat arrays, indexed by `row * width + col`,
+/// for searches that handle positions one at a time. `dead` marks the squares
+/// a block can never be pushed to a goal from.
+pub struct Grid {
+  pub width: uint,
+  pub height: uint,
+  walls: Vec<bool>,
+  goals: Vec<bool>,
+  dead: Vec<bool>,
+  boxes: Vec<uint>,
+  man: uint,
+}
+
+impl Grid {
+  pub fn new(soko: &SokoAnnotatedBoard) -> Grid {
+    let (height, width) = (soko.height(), soko.width());
+    let mut grid = Grid {
+      width: width,
+      height: height,
+      walls: Vec::with_capacity(width * height),
+      goals: Vec::with_capacity(width * height),
+      dead: Vec::with_capacity(width * height),
+      boxes: Vec::new(),
+      man: 0,
+    };
+    for row in range(0, height) {
+      for col in range(0, width) {
+        grid.walls.push(soko.isWall(row, col));
+        grid.goals.push(soko.isGoal(row, col));
+        grid.dead.push(!soko.isProductive(row, col));
+        if soko.hasBlock(row, col) {
+          grid.boxes.push(row * width + col);
+        }
+        if soko.hasMan(row, col) {
+          grid.man = row * width + col;
+        }
+      }
+    }
+    grid
+  }
+
+  pub fn cells(&self) -> uint {
+    self.width * self.height
+  }
+
+  pub fn cell(&self, row: uint, col: uint) -> uint {
+    row * self.width + col
+  }
+
+  pub fn coords(&self, cell: uint) -> (uint, uint) {
+    (cell / self.width, cell % self.width)
+  }
+
+  /// The square next to `cell` in direction `dir`, if it is on the board.
+  pub fn neighbour(&self, cell: uint, dir: Direction) -> Option<uint> {
+    let (row, col) = self.coords(cell);
+    match dir {
+      Up if row > 0 => Some(cell - self.width),
+      Down if row + 1 < self.height => Some(cell + self.width),
+      Left if col > 0 => Some(cell - 1),
+      Right if col + 1 < self.width => Some(cell + 1),
+      _ => None,
+    }
+  }
+
+  pub fn isWall(&self, cell: uint) -> bool {
+    self.walls[cell]
+  }
+
+  pub fn isGoal(&self, cell: uint) -> bool {
+    self.goals[cell]
+  }
+
+  pub fn isDead(&self, cell: uint) -> bool {
+    self.dead[cell]
+  }
+
+  /// The squares of the blocks in the initial position, in ascending order.
+  pub fn initial_boxes<'a>(&'a self) -> &'a [uint] {
+    self.boxes.as_slice()
+  }
+
+  pub fn initial_man(&self) -> uint {
+    self.man
+  }
+
+  /// Marks each square with a block, given the squares of the blocks.
+  pub fn occupancy(&self, boxes: &[uint]) -> Vec<bool> {
+    let mut occupied = Vec::from_elem(self.cells(), false);
+    for &b in boxes.iter() {
+      *occupied.get_mut(b) = true;
+    }
+    occupied
+  }
+
+  /// The squares the man can walk to from `from` without moving a block.
+  pub fn reach(&self, occupied: &[bool], from: uint) -> Vec<bool> {
+    let mut reached = Vec::from_elem(self.cells(), false);
+    let mut todo = vec!(from);
+    *reached.get_mut(from) = true;
+    while !todo.is_empty() {
+      let cell = todo.pop().unwrap();
+      for &dir in DIRECTIONS.iter() {
+        match self.neighbour(cell, dir) {
+          Some(next) if !reached[next] && !self.walls[next] && !occupied[next] => {
+            *reached.get_mut(next) = true;
+            todo.push(next);
+          }
+          _ => {}
+        }
+      }
+    }
+    reached
+  }
+
+  /// Whether the block on `cell` can never be moved again. A block is stuck
+  /// along an axis when there is a wall on either side of it, when it cannot
+  /// stay on either square it would be pushed to, or when the block on either
+  /// side is frozen itself. It is frozen when it is stuck along both axes.
+  /// While its neighbours are checked it counts as a wall, in `fixed`, which
+  /// keeps the check from going round in circles. `fixed` has a square for
+  /// every cell, all `false`, and is left that way.
+  pub fn isFrozen(&self, occupied: &[bool], fixed: &mut [bool], cell: uint) -> bool {
+    fixed[cell] = true;
+    let result = self.stuck(occupied, fixed, cell, Up) && self.stuck(occupied, fixed, cell, Left);
+    fixed[cell] = false;
+    result
+  }
+
+  fn stuck(&self, occupied: &[bool], fixed: &mut [bool], cell: uint, dir: Direction) -> bool {
+    let (a, b) = match (self.neighbour(cell, dir), self.neighbour(cell, dir.opposite())) {
+      (Some(a), Some(b)) => (a, b),
+      _ => return true,
+    };
+    if self.walls[a] || self.walls[b] || fixed[a] || fixed[b] {
+      return true;
+    }
+    if self.dead[a] && self.dead[b] {
+      return true;
+    }
+    (occupied[a] && self.isFrozen(occupied, fixed, a)) || (occupied[b] && self.isFrozen(occupied, fixed, b))
+  }
+
+  /// `true` if, after a block was pushed onto `to`, a block that is not on a
+  /// goal can never be moved again. Only the blocks joined to `to` through
+  /// neighbouring blocks can have frozen by the push, so only those are
+  /// checked. `fixed` is as for `isFrozen`.
+  pub fn isFreezeDeadlock(&self, occupied: &[bool], fixed: &mut [bool], to: uint) -> bool {
+    let mut group = vec!(to);
+    let mut head = 0;
+    while head < group.len() {
+      let cell = group[head];
+      head += 1;
+      if !self.goals[cell] && self.isFrozen(occupied, fixed, cell) {
+        return true;
+      }
+      for &dir in DIRECTIONS.iter() {
+        match self.neighbour(cell, dir) {
+          Some(next) if occupied[next] && !group.contains(&next) => group.push(next),
+          _ => {}
+        }
+      }
+    }
+    false
+  }
+
+  /// A shortest walk for the man from `from` to `to` that moves no block.
+  pub fn walk(&self, occupied: &[bool], from: uint, to: uint) -> Option<Vec<Direction>> {
+    let mut came_from: Vec<Option<Direction>> = Vec::from_elem(self.cells(), None);
+    let mut visited = Vec::from_elem(self.cells(), false);
+    let mut queue = vec!(from);
+    let mut head = 0;
+    *visited.get_mut(from) = true;
+    while head < queue.len() && !visited[to] {
+      let cell = queue[head];
+      head += 1;
+      for &dir in DIRECTIONS.iter() {
+        match self.neighbour(cell, dir) {
+          Some(next) if !visited[next] && !self.walls[next] && !occupied[next] => {
+            *visited.get_mut(next) = true;
+            *came_from.get_mut(next) = Some(dir);
+            queue.push(next);
+          }
+          _ => {}
+        }
+      }
+    }
+    if !visited[to] {
+      return None;
+    }
+    let mut path = Vec::new();
+    let mut cell = to;
+    while cell != from {
+      let dir = came_from[cell].unwrap();
+      path.push(dir);
+      cell = self.neighbour(cell, dir.opposite()).unwrap();
+    }
+    path.reverse();
+    Some(path)
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use sokoannotatedboard::{SokoAnnotatedBoard};
+  use sokoboard::{SokoBoard};
+  use super::{Grid};
+
+  fn grid(board: &str) -> Grid {
+    Grid::new(&SokoAnnotatedBoard::fromSokoBoard(SokoBoard::parse(board).unwrap()))
+  }
+
+  /// Whether pushing a block onto (`row`,`col`), with blocks on `boxes`,
+  /// freezes a block off its goal.
+  fn frozen(grid: &Grid, boxes: &[(uint, uint)], (row, col): (uint, uint)) -> bool {
+    let cells: Vec<uint> = boxes.iter().map(|&(r, c)| grid.cell(r, c)).collect();
+    let occupied = grid.occupancy(cells.as_slice());
+    let mut fixed = Vec::from_elem(grid.cells(), false);
+    let result = grid.isFreezeDeadlock(occupied.as_slice(), fixed.as_mut_slice(), grid.cell(row, col));
+    assert!(fixed.iter().all(|&f| !f));
+    result
+  }
+
+  static OPEN: &'static str = "#######\n#.   .#\n#     #\n#@    #\n#######";
+
+  #[test]
+  fn blocks_against_a_wall_freeze_each_other() {
+    let grid = grid(OPEN);
+    assert!(frozen(&grid, &[(1, 2), (1, 3)], (1, 3)));
+    assert!(frozen(&grid, &[(1, 1), (1, 2)], (1, 2)));
+    assert!(frozen(&grid, &[(1, 1), (1, 2)], (1, 1)));
+  }
+
+  #[test]
+  fn movable_blocks_and_blocks_on_goals_are_fine() {
+    let grid = grid(OPEN);
+    assert!(!frozen(&grid, &[(2, 3)], (2, 3)));
+    assert!(!frozen(&grid, &[(1, 3)], (1, 3)));
+    assert!(!frozen(&grid, &[(1, 1)], (1, 1)));
+    assert!(!frozen(&grid, &[(2, 2), (2, 3)], (2, 3)));
+  }
+}
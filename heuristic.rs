@@ -0,0 +1,185 @@
+use std::int;
+
+use super::grid::{Grid, DIRECTIONS};
+use super::sokoannotatedboard::{SokoAnnotatedBoard};
+
+/// Push distances from every square to every goal, for estimating how many
+/// pushes a position still needs. Other blocks are ignored, so the distances
+/// never overestimate.
+pub struct Heuristic {
+  goals: Vec<uint>,
+  /// `distances[g][cell]` is the number of pushes needed to get a block from
+  /// `cell` to the `g`th goal, if it can get there at all.
+  distances: Vec<Vec<Option<uint>>>,
+}
+
+impl Heuristic {
+  pub fn new(grid: &Grid) -> Heuristic {
+    let goals: Vec<uint> = range(0, grid.cells()).filter(|&cell| grid.isGoal(cell)).collect();
+    let distances = goals.iter().map(|&goal| pull_distances(grid, goal)).collect();
+    Heuristic {
+      goals: goals,
+      distances: distances,
+    }
+  }
+
+  /// A lower bound on the pushes needed to get the blocks on the squares
+  /// `boxes` onto the goals, or `None` if they cannot all get there. Each
+  /// block is matched to its own goal such that the total distance is as
+  /// small as possible.
+  pub fn lower_bound(&self, boxes: &[uint]) -> Option<uint> {
+    let cost: Vec<Vec<Option<uint>>> = boxes.iter().map(|&b| {
+      self.distances.iter().map(|distances| distances[b]).collect()
+    }).collect();
+    min_cost_assignment(cost.as_slice(), self.goals.len())
+  }
+}
+
+/// A lower bound on the pushes needed to solve the puzzle from its initial
+/// position, or `None` if some block cannot reach any goal.
+pub fn push_lower_bound(soko: &SokoAnnotatedBoard) -> Option<uint> {
+  let grid = Grid::new(soko);
+  Heuristic::new(&grid).lower_bound(grid.initial_boxes())
+}
+
+/// The number of pushes needed to get a block from each square to `goal`,
+/// found by pulling a block away from the goal. Blocks only ever pass
+/// through productive squares.
+fn pull_distances(grid: &Grid, goal: uint) -> Vec<Option<uint>> {
+  let mut distances = Vec::from_elem(grid.cells(), None);
+  let mut queue = vec!(goal);
+  let mut head = 0;
+  *distances.get_mut(goal) = Some(0u);
+  while head < queue.len() {
+    let cell = queue[head];
+    head += 1;
+    let distance = distances[cell].unwrap();
+    for &dir in DIRECTIONS.iter() {
+      // The block came from `from`, pushed by the man standing on `man`.
+      let from = match grid.neighbour(cell, dir.opposite()) {
+        Some(from) if !grid.isWall(from) && !grid.isDead(from) => from,
+        _ => continue,
+      };
+      match grid.neighbour(from, dir.opposite()) {
+        Some(man) if !grid.isWall(man) => {}
+        _ => continue,
+      }
+      if distances[from].is_none() {
+        *distances.get_mut(from) = Some(distance + 1);
+        queue.push(from);
+      }
+    }
+  }
+  distances
+}
+
+/// The smallest total cost of assigning each row of `cost` to a different
+/// one of `columns` columns, using the Hungarian method. `None` entries can
+/// not be chosen; the result is `None` if no assignment avoids them.
+fn min_cost_assignment(cost: &[Vec<Option<uint>>], columns: uint) -> Option<uint> {
+  let (n, m) = (cost.len(), columns);
+  if n > m {
+    return None;
+  }
+  // An impossible pair costs more than every possible pair together, so it
+  // is only chosen when there is no other way.
+  let mut impossible = 1i;
+  for row in cost.iter() {
+    for c in row.iter() {
+      impossible += c.unwrap_or(0) as int;
+    }
+  }
+  let weight = |i: uint, j: uint| -> int { cost[i-1][j-1].map_or(impossible, |c| c as int) };
+
+  // Rows and columns are numbered from 1; column 0 is a sentinel.
+  let mut u = Vec::from_elem(n+1, 0i);
+  let mut v = Vec::from_elem(m+1, 0i);
+  let mut row_of = Vec::from_elem(m+1, 0u);
+  let mut way = Vec::from_elem(m+1, 0u);
+  for i in range(1, n+1) {
+    *row_of.get_mut(0) = i;
+    let mut j0 = 0u;
+    let mut minv = Vec::from_elem(m+1, int::MAX);
+    let mut used = Vec::from_elem(m+1, false);
+    loop {
+      *used.get_mut(j0) = true;
+      let i0 = row_of[j0];
+      let mut delta = int::MAX;
+      let mut j1 = 0u;
+      for j in range(1, m+1) {
+        if !used[j] {
+          let reduced = weight(i0, j) - u[i0] - v[j];
+          if reduced < minv[j] {
+            *minv.get_mut(j) = reduced;
+            *way.get_mut(j) = j0;
+          }
+          if minv[j] < delta {
+            delta = minv[j];
+            j1 = j;
+          }
+        }
+      }
+      for j in range(0, m+1) {
+        if used[j] {
+          *u.get_mut(row_of[j]) += delta;
+          *v.get_mut(j) -= delta;
+        } else {
+          *minv.get_mut(j) -= delta;
+        }
+      }
+      j0 = j1;
+      if row_of[j0] == 0 {
+        break;
+      }
+    }
+    while j0 != 0 {
+      let j1 = way[j0];
+      *row_of.get_mut(j0) = row_of[j1];
+      j0 = j1;
+    }
+  }
+
+  let mut total = 0;
+  for j in range(1, m+1) {
+    if row_of[j] != 0 {
+      match cost[row_of[j]-1][j-1] {
+        Some(c) => total += c,
+        None => return None,
+      }
+    }
+  }
+  Some(total)
+}
+
+#[cfg(test)]
+mod test {
+  use sokoannotatedboard::{SokoAnnotatedBoard};
+  use sokoboard::{SokoBoard};
+  use super::{min_cost_assignment, push_lower_bound};
+
+  #[test]
+  fn assigns_at_the_lowest_cost() {
+    let cost = vec!(vec!(Some(1u), Some(2u)), vec!(Some(2u), Some(4u)));
+    assert_eq!(min_cost_assignment(cost.as_slice(), 2), Some(4));
+    let cost = vec!(vec!(Some(3u), Some(1u), Some(5u)));
+    assert_eq!(min_cost_assignment(cost.as_slice(), 3), Some(1));
+    assert_eq!(min_cost_assignment(&[], 2), Some(0));
+  }
+
+  #[test]
+  fn avoids_impossible_pairs() {
+    // The cheap pair of the second row has to give way, whatever it costs.
+    let cost = vec!(vec!(None, Some(100u)), vec!(Some(1u), Some(1u)));
+    assert_eq!(min_cost_assignment(cost.as_slice(), 2), Some(101));
+    let cost = vec!(vec!(Some(1u), None), vec!(Some(3u), None));
+    assert_eq!(min_cost_assignment(cost.as_slice(), 2), None);
+    let cost = vec!(vec!(Some(1u)), vec!(Some(1u)));
+    assert_eq!(min_cost_assignment(cost.as_slice(), 1), None);
+  }
+
+  #[test]
+  fn bounds_the_pushes_of_a_level() {
+    let board = SokoBoard::parse("#######\n#@ $ .#\n#######").unwrap();
+    assert_eq!(push_lower_bound(&SokoAnnotatedBoard::fromSokoBoard(board)), Some(2));
+  }
+}
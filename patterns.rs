@@ -0,0 +1,380 @@
+use collections::{HashMap, HashSet};
+use std::io::{File, IoResult};
+use std::path::{Path};
+use std::strbuf::StrBuf;
+
+use super::grid::{Grid, DIRECTIONS};
+use super::sokoboard::{Direction, Up, Down, Left, Right};
+
+/// The patterns `sokoban patterns` writes, built into the solver so they need
+/// not be generated at every start. Regenerate `patterns.txt` with
+/// `sokoban patterns patterns.txt` whenever the generator changes.
+static BUILTIN: &'static str = include_str!("patterns.txt");
+
+/// The side of the square windows the patterns cover.
+pub static SIZE: uint = 3;
+
+/// A window with a ring of floor around it. Blocks pushed onto the ring have
+/// left the window, and the man can walk all the way round it.
+static SIDE: uint = SIZE + 2;
+
+/// A deadlock pattern over a window of SIZE x SIZE squares, numbered row by
+/// row. `walls` and `boxes` are bit masks of the squares holding walls and
+/// blocks; the other squares are floor. `stuck` marks the blocks that can
+/// never be moved again, whatever lies outside the window.
+#[derive(Eq, Clone)]
+pub struct Pattern {
+  pub walls: uint,
+  pub boxes: uint,
+  pub stuck: uint,
+}
+
+/// A set of deadlock patterns. Any of them found on a board, with one of its
+/// stuck blocks off a goal, means the puzzle cannot be solved any more. Only
+/// windows of SIZE x SIZE squares are supported, and patterns say nothing
+/// about goals: a window is only a deadlock if a stuck block is off its goal.
+pub struct Patterns {
+  count: uint,
+  by_walls: HashMap<uint, Vec<Pattern>>,
+}
+
+impl Patterns {
+  /// No patterns at all.
+  pub fn new() -> Patterns {
+    Patterns {
+      count: 0,
+      by_walls: HashMap::new(),
+    }
+  }
+
+  pub fn len(&self) -> uint {
+    self.count
+  }
+
+  fn add(&mut self, pattern: Pattern) {
+    self.by_walls.find_or_insert(pattern.walls, Vec::new()).push(pattern);
+    self.count += 1;
+  }
+
+  /// The patterns with exactly the walls in `walls`.
+  pub fn with_walls<'a>(&'a self, walls: uint) -> &'a [Pattern] {
+    match self.by_walls.find(&walls) {
+      Some(patterns) => patterns.as_slice(),
+      None => &[],
+    }
+  }
+
+  /// Finds every pattern by trying each way of filling a window with walls,
+  /// blocks and floor, and pushing the blocks around in every possible way.
+  /// Everything outside the window counts as floor, so no pattern depends on
+  /// what surrounds it.
+  pub fn generate() -> Patterns {
+    let mut patterns = Patterns::new();
+    let mut count = 1u;
+    for _ in range(0, SIZE * SIZE) {
+      count *= 3;
+    }
+    for code in range(0, count) {
+      let (mut walls, mut boxes, mut rest) = (0u, 0u, code);
+      for i in range(0, SIZE * SIZE) {
+        match rest % 3 {
+          1 => walls |= 1 << i,
+          2 => boxes |= 1 << i,
+          _ => {}
+        }
+        rest /= 3;
+      }
+      if boxes == 0 {
+        continue;
+      }
+      let stuck = boxes & !pushed_squares(walls, boxes);
+      if stuck != 0 {
+        patterns.add(Pattern { walls: walls, boxes: boxes, stuck: stuck });
+      }
+    }
+    patterns
+  }
+
+  /// The patterns in `patterns.txt`, which is built into the solver. They
+  /// are read at every start, so a corrupt file is reported as an error.
+  pub fn builtin() -> Result<Patterns, StrBuf> {
+    Patterns::parse(BUILTIN, "patterns.txt")
+  }
+
+  /// Reads patterns written by `save`.
+  pub fn load(path: &Path) -> Result<Patterns, StrBuf> {
+    match File::open(path).read_to_str() {
+      Ok(contents) => Patterns::parse(contents.as_slice(), format!("{}", path.display()).as_slice()),
+      Err(e) => Err(StrBuf::from_owned_str(format!("Cannot read {}: {}", path.display(), e))),
+    }
+  }
+
+  /// Reads patterns in the format of `save` from `contents`; `name` says
+  /// where they came from in error messages.
+  fn parse(contents: &str, name: &str) -> Result<Patterns, StrBuf> {
+    let mut patterns = Patterns::new();
+    let mut rows = Vec::new();
+    for (lineIx, line) in contents.lines_any().enumerate() {
+      if line.starts_with(";") {
+        continue;
+      }
+      if line.trim().is_empty() {
+        if rows.len() != 0 {
+          return Err(StrBuf::from_owned_str(format!("{}: incomplete pattern before line {}", name, lineIx+1)));
+        }
+        continue;
+      }
+      if line.char_len() != SIZE {
+        return Err(StrBuf::from_owned_str(format!("{}: line {} is not {} squares wide", name, lineIx+1, SIZE)));
+      }
+      rows.push(line);
+      if rows.len() == SIZE {
+        match parse_pattern(rows.as_slice()) {
+          Some(pattern) => patterns.add(pattern),
+          None => return Err(StrBuf::from_owned_str(format!("{}: invalid pattern ending at line {}", name, lineIx+1))),
+        }
+        rows.clear();
+      }
+    }
+    if rows.len() != 0 {
+      return Err(StrBuf::from_owned_str(format!("{}: incomplete pattern at the end", name)));
+    }
+    Ok(patterns)
+  }
+
+  /// Writes the patterns to `path`, one window per paragraph: `#` is a wall,
+  /// `-` floor, `$` a block and `x` a block that can never be moved again.
+  /// The patterns are sorted, so the same patterns always give the same file.
+  pub fn save(&self, path: &Path) -> IoResult<()> {
+    let mut file = try!(File::create(path));
+    try!(file.write_line(format!("; Deadlock patterns for {0}x{0} windows, written by `sokoban patterns`.", SIZE).as_slice()));
+    try!(file.write_line("; '#' wall, '-' floor, '$' block, 'x' block that can never be moved again."));
+    for pattern in self.sorted().iter() {
+      try!(file.write_line(""));
+      for row in range(0, SIZE) {
+        let mut line = StrBuf::with_capacity(SIZE);
+        for col in range(0, SIZE) {
+          let bit = 1 << (row * SIZE + col);
+          line.push_char(if pattern.walls & bit != 0 {
+            '#'
+          } else if pattern.stuck & bit != 0 {
+            'x'
+          } else if pattern.boxes & bit != 0 {
+            '$'
+          } else {
+            '-'
+          });
+        }
+        try!(file.write_line(line.as_slice()));
+      }
+    }
+    Ok(())
+  }
+
+  /// Every pattern, sorted by walls, then blocks, then stuck blocks.
+  fn sorted<'a>(&'a self) -> Vec<&'a Pattern> {
+    let mut sorted: Vec<&Pattern> = self.by_walls.values().flat_map(|patterns| patterns.iter()).collect();
+    sorted.sort_by(|a, b| (a.walls, a.boxes, a.stuck).cmp(&(b.walls, b.boxes, b.stuck)));
+    sorted
+  }
+
+  /// `true` if, with blocks on the squares in `occupied`, some window around
+  /// `cell` matches a pattern with a stuck block off its goal.
+  pub fn isDeadlock(&self, grid: &Grid, occupied: &[bool], cell: uint) -> bool {
+    let (row, col) = grid.coords(cell);
+    for dr in range(0, SIZE) {
+      for dc in range(0, SIZE) {
+        if row < dr || col < dc || row - dr + SIZE > grid.height || col - dc + SIZE > grid.width {
+          continue;
+        }
+        let (top, left) = (row - dr, col - dc);
+        let (mut walls, mut boxes, mut goals) = (0u, 0u, 0u);
+        for i in range(0, SIZE * SIZE) {
+          let square = grid.cell(top + i / SIZE, left + i % SIZE);
+          if grid.isWall(square) {
+            walls |= 1 << i;
+          }
+          if occupied[square] {
+            boxes |= 1 << i;
+          }
+          if grid.isGoal(square) {
+            goals |= 1 << i;
+          }
+        }
+        if self.with_walls(walls).iter().any(|p| p.boxes == boxes && p.stuck & !goals != 0) {
+          return true;
+        }
+      }
+    }
+    false
+  }
+}
+
+/// Reads a pattern from its `SIZE` rows.
+fn parse_pattern(rows: &[&str]) -> Option<Pattern> {
+  let mut pattern = Pattern { walls: 0, boxes: 0, stuck: 0 };
+  for (row, line) in rows.iter().enumerate() {
+    for (col, chr) in line.chars().enumerate() {
+      let bit = 1 << (row * SIZE + col);
+      match chr {
+        '#' => pattern.walls |= bit,
+        '-' => {}
+        '$' => pattern.boxes |= bit,
+        'x' => {
+          pattern.boxes |= bit;
+          pattern.stuck |= bit;
+        }
+        _ => return None,
+      }
+    }
+  }
+  if pattern.stuck == 0 { None } else { Some(pattern) }
+}
+
+/// The bit of the window square at `cell` of the ringed window, if it is not
+/// on the ring.
+fn window_bit(cell: uint) -> Option<uint> {
+  let (row, col) = (cell / SIDE, cell % SIDE);
+  if row == 0 || col == 0 || row == SIDE - 1 || col == SIDE - 1 {
+    None
+  } else {
+    Some((row - 1) * SIZE + col - 1)
+  }
+}
+
+fn neighbour(cell: uint, dir: Direction) -> Option<uint> {
+  let (row, col) = (cell / SIDE, cell % SIDE);
+  match dir {
+    Up if row > 0 => Some(cell - SIDE),
+    Down if row + 1 < SIDE => Some(cell + SIDE),
+    Left if col > 0 => Some(cell - 1),
+    Right if col + 1 < SIDE => Some(cell + 1),
+    _ => None,
+  }
+}
+
+fn blocked(walls: uint, boxes: uint, cell: uint) -> bool {
+  window_bit(cell).map_or(false, |bit| (walls | boxes) & (1 << bit) != 0)
+}
+
+/// The squares of the ringed window the man can walk to from `from`.
+fn reach(walls: uint, boxes: uint, from: uint) -> Vec<bool> {
+  let mut reached = Vec::from_elem(SIDE * SIDE, false);
+  let mut todo = vec!(from);
+  *reached.get_mut(from) = true;
+  while !todo.is_empty() {
+    let cell = todo.pop().unwrap();
+    for &dir in DIRECTIONS.iter() {
+      match neighbour(cell, dir) {
+        Some(next) if !reached[next] && !blocked(walls, boxes, next) => {
+          *reached.get_mut(next) = true;
+          todo.push(next);
+        }
+        _ => {}
+      }
+    }
+  }
+  reached
+}
+
+/// The squares of the window from which a block, starting from `boxes`, can
+/// ever be pushed, with the man starting anywhere.
+fn pushed_squares(walls: uint, boxes: uint) -> uint {
+  let region = |boxes: uint, man: uint| -> uint {
+    let reached = reach(walls, boxes, man);
+    range(0, SIDE * SIDE).find(|&cell| reached[cell]).unwrap()
+  };
+  let mut pushed = 0u;
+  let mut seen = HashSet::new();
+  let mut todo = Vec::new();
+  for cell in range(0, SIDE * SIDE) {
+    if !blocked(walls, boxes, cell) {
+      let state = (boxes, region(boxes, cell));
+      if seen.insert(state) {
+        todo.push(state);
+      }
+    }
+  }
+  while !todo.is_empty() && boxes & !pushed != 0 {
+    let (current, man) = todo.pop().unwrap();
+    let reached = reach(walls, current, man);
+    for cell in range(0, SIDE * SIDE) {
+      let bit = match window_bit(cell) {
+        Some(bit) if current & (1 << bit) != 0 => bit,
+        _ => continue,
+      };
+      for &dir in DIRECTIONS.iter() {
+        let (from, to) = match (neighbour(cell, dir.opposite()), neighbour(cell, dir)) {
+          (Some(from), Some(to)) => (from, to),
+          _ => continue,
+        };
+        if !reached[from] || blocked(walls, current, to) {
+          continue;
+        }
+        pushed |= 1 << bit;
+        let mut next = current & !(1 << bit);
+        match window_bit(to) {
+          Some(to_bit) => next |= 1 << to_bit,
+          None => {}
+        }
+        let state = (next, region(next, cell));
+        if seen.insert(state) {
+          todo.push(state);
+        }
+      }
+    }
+  }
+  pushed
+}
+
+#[cfg(test)]
+mod test {
+  use std::io::{TempDir};
+  use grid::{Grid};
+  use sokoannotatedboard::{SokoAnnotatedBoard};
+  use sokoboard::{SokoBoard};
+  use super::{Patterns};
+
+  static OPEN: &'static str = "########\n#      #\n# ..   #\n# ..   #\n#      #\n#@     #\n########";
+
+  /// Whether one of the built-in patterns is found around (`row`,`col`)
+  /// with blocks on `boxes`.
+  fn deadlocked(boxes: &[(uint, uint)], (row, col): (uint, uint)) -> bool {
+    let grid = Grid::new(&SokoAnnotatedBoard::fromSokoBoard(SokoBoard::parse(OPEN).unwrap()));
+    let cells: Vec<uint> = boxes.iter().map(|&(r, c)| grid.cell(r, c)).collect();
+    let occupied = grid.occupancy(cells.as_slice());
+    Patterns::builtin().unwrap().isDeadlock(&grid, occupied.as_slice(), grid.cell(row, col))
+  }
+
+  #[test]
+  fn saved_patterns_load_again() {
+    let generated = Patterns::generate();
+    let dir = TempDir::new("patterns").unwrap();
+    let path = dir.path().join("patterns.txt");
+    generated.save(&path).unwrap();
+    let loaded = Patterns::load(&path).unwrap();
+    assert!(loaded.len() > 0);
+    assert!(loaded.sorted() == generated.sorted());
+  }
+
+  #[test]
+  fn builtin_patterns_are_up_to_date() {
+    let builtin = Patterns::builtin().unwrap();
+    assert!(builtin.sorted() == Patterns::generate().sorted());
+  }
+
+  #[test]
+  fn a_square_of_blocks_is_a_deadlock_off_the_goals() {
+    assert!(deadlocked(&[(2, 4), (2, 5), (3, 4), (3, 5)], (2, 4)));
+    assert!(!deadlocked(&[(2, 2), (2, 3), (3, 2), (3, 3)], (2, 2)));
+    assert!(!deadlocked(&[(4, 3)], (4, 3)));
+    assert!(!deadlocked(&[(2, 4), (2, 5), (3, 4)], (2, 4)));
+  }
+
+  #[test]
+  fn corrupt_patterns_are_an_error() {
+    assert!(Patterns::parse("x-\n--\n", "bad").is_err());
+    assert!(Patterns::parse("xx-\nxx-\n", "bad").is_err());
+    assert!(Patterns::parse("xx-\nxx-\n--?\n", "bad").is_err());
+  }
+}
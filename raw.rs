@@ -1,4 +1,4 @@
-use libc::{size_t,c_int};
+use libc::{size_t,c_int,c_void};
 
 pub type BDD = u64;
 pub type BDDSET = u64;
@@ -12,6 +12,9 @@ extern {
   pub static sylvan_false: BDD;
   pub static sylvan_invalid: BDD;
   pub fn lace_init(workers: c_int, x: size_t, stacksize: size_t);
+  pub fn lace_startup(stacksize: size_t, cb: *c_void, arg: *mut c_void);
+  pub fn lace_workers() -> size_t;
+  pub fn lace_exit();
   pub fn sylvan_init(datasize: size_t, cachesize: size_t, granularity: c_int);
   pub fn sylvan_quit();
   pub fn sylvan_ithvar(var: BDDVAR) -> BDD;
@@ -83,10 +86,24 @@ extern {
 
 }
 
-pub unsafe fn raw_init() {
-  lace_init(1,100000,0);
-  sylvan_init(26,22,2);
+/// Starts lace with `workers` workers and runs `root` as its first task, with
+/// `arg` as its last argument. The calling thread becomes worker 0 and
+/// executes `root`, so everything `root` does runs as a lace task that the
+/// other workers can steal work from. Returns once `root` has returned and
+/// lace has been shut down.
+pub unsafe fn raw_run(workers: uint, dqsize: uint, root: extern "C" fn(*c_void, *c_void, *mut c_void), arg: *mut c_void) {
+  lace_init(workers as c_int, dqsize as size_t, 0);
+  lace_startup(0, root as *c_void, arg);
+  lace_exit();
 }
 
+/// Starts sylvan. Must be called from a lace task, e.g. the root of `raw_run`.
+pub unsafe fn raw_init(datasize: uint, cachesize: uint, granularity: uint) {
+  sylvan_init(datasize as size_t, cachesize as size_t, granularity as c_int);
+  // Safe as long as every live BDD is referenced, which `bdd::Bdd` does.
+  sylvan_gc_enable();
+}
 
-
+pub unsafe fn raw_quit() {
+  sylvan_quit();
+}
@@ -1,5 +1,14 @@
-use super::bdd::{Bdd};
+use collections::{HashMap, HashSet};
+use std::strbuf::StrBuf;
+use time::{precise_time_ns};
+
+use super::bdd::{Bdd, Sylvan};
+use super::grid::{Grid};
+use super::patterns::{Patterns, SIZE};
 use super::sokoboard::{SokoBoard, Field, Block, Man, Wall, Goal, BlockOnGoal, ManOnGoal};
+use super::sokoboard::{Direction, Up, Down, Left, Right, Move};
+use super::solver::{Solution, Stats, SolveError, Unsolvable, InvalidBoard, Unsupported, Limits, Solver, check_limits};
+use super::solver::{Progress, LayerReport};
 
 /// Represents a sokoban playing field. The individual squares are annotated
 /// with some data that is gathered at initialization.
@@ -25,11 +34,73 @@ impl SokoAnnotatedBoard {
     };
     reachability(&mut result);
     productivity(&mut result);
-    sanityCheck(&result);
     assignIDs(&mut result);
     result
   }
 
+  /// The number of rows of the board.
+  pub fn height(&self) -> uint {
+    self.board.len()
+  }
+
+  /// The number of columns of the board.
+  pub fn width(&self) -> uint {
+    self.board[0].len()
+  }
+
+  pub fn isWall(&self, row: uint, col: uint) -> bool {
+    self.board[row][col].field == Wall
+  }
+
+  pub fn isGoal(&self, row: uint, col: uint) -> bool {
+    self.board[row][col].isGoal()
+  }
+
+  pub fn hasBlock(&self, row: uint, col: uint) -> bool {
+    self.board[row][col].hasBlock()
+  }
+
+  pub fn hasMan(&self, row: uint, col: uint) -> bool {
+    self.board[row][col].hasMan()
+  }
+
+  /// `true` if a block on this square may still reach a goal.
+  pub fn isProductive(&self, row: uint, col: uint) -> bool {
+    self.board[row][col].productive
+  }
+
+  /// `true` if the man can get to this square from his starting position.
+  pub fn isReachable(&self, row: uint, col: uint) -> bool {
+    self.board[row][col].reachable
+  }
+
+  /// Rejects boards the man can walk off or where the number of blocks and
+  /// goals differ, and puzzles with a block on an unproductive square or a
+  /// frozen block.
+  pub fn validate(&self) -> Result<(), SolveError> {
+    sanityCheck(self)
+  }
+
+  /// `true` if, with blocks on the squares in `blocks`, a block that is not
+  /// on a goal can never be moved again.
+  pub fn isFreezeDeadlock(&self, blocks: &[(uint, uint)]) -> bool {
+    let grid = Grid::new(self);
+    let cells: Vec<uint> = blocks.iter().map(|&(row, col)| grid.cell(row, col)).collect();
+    let occupied = grid.occupancy(cells.as_slice());
+    let mut fixed = Vec::from_elem(grid.cells(), false);
+    cells.iter().any(|&cell| !grid.isGoal(cell) && grid.isFrozen(occupied.as_slice(), fixed.as_mut_slice(), cell))
+  }
+
+  /// The tunnels of the board, each listed once.
+  pub fn tunnels(&self) -> Vec<Tunnel> {
+    find_tunnels(self.board.as_slice())
+  }
+
+  /// The goal rooms of the board. A room holding another one is left out.
+  pub fn goalRooms(&self) -> Vec<GoalRoom> {
+    find_goal_rooms(self.board.as_slice())
+  }
+
   /*
   /// Prints a representation of the squares that have been identified as 'productive';
   /// that is, all squares that coudl contain a box without the game becomming
@@ -115,7 +186,7 @@ fn valid_index<T>(row: uint, col:uint, fields: &[Vec<T>]) -> bool {
 fn reachability(soko: &mut SokoAnnotatedBoard) {
 
   fn visit(board: &mut [Vec<AnnotatedField>], row: uint, col: uint) {
-    if !(board[row][col].reachable || board[row][col].field == Wall) {
+    if valid_index(row,col,board) && !(board[row][col].reachable || board[row][col].field == Wall) {
       board[row][col].reachable = true;
       visit(board, row+1, col);
       visit(board, row-1, col);
@@ -166,14 +237,163 @@ fn productivity(soko: &mut SokoAnnotatedBoard) {
   }
 }
 
-fn sanityCheck(soko: &SokoAnnotatedBoard) {
+/// A straight run of squares with a wall on either side, going in
+/// `direction`, that a block can be pushed through but never turned in. The
+/// squares are not goals, so a block has no business stopping in it.
+pub struct Tunnel {
+  pub direction: Direction,
+  pub squares: Vec<(uint, uint)>,
+}
+
+/// A part of the board holding goals but no blocks, which the man can only
+/// get into through `entrance`. Blocks are pushed into it one at a time.
+pub struct GoalRoom {
+  pub entrance: (uint, uint),
+  pub squares: Vec<(uint, uint)>,
+}
+
+/// `true` if (`row`,`col`) is part of a tunnel going in direction `dir`.
+fn in_tunnel(fields: &[Vec<AnnotatedField>], row: uint, col: uint, dir: Direction) -> bool {
+  let (dr, dc) = delta(dir);
+  let wall = |r: uint, c: uint| !valid_index(r, c, fields) || fields[r][c].field == Wall;
+  // Swapping the offsets gives the squares on either side.
+  valid_index(row, col, fields)
+      && fields[row][col].reachable
+      && fields[row][col].productive
+      && !fields[row][col].isGoal()
+      && wall(row+dc, col+dr)
+      && wall(row-dc, col-dr)
+}
+
+/// Finds the tunnels going down and right; the same squares also make up the
+/// tunnels going up and left.
+fn find_tunnels(fields: &[Vec<AnnotatedField>]) -> Vec<Tunnel> {
+  let mut result = Vec::new();
+  for &dir in [Down, Right].iter() {
+    let (dr, dc) = delta(dir);
+    for row in fields.iter() {
+      for field in row.iter() {
+        let (mut r, mut c) = (field.row, field.col);
+        if !in_tunnel(fields, r, c, dir) || in_tunnel(fields, r-dr, c-dc, dir) {
+          continue;
+        }
+        let mut squares = Vec::new();
+        while in_tunnel(fields, r, c, dir) {
+          squares.push((r, c));
+          r += dr;
+          c += dc;
+        }
+        result.push(Tunnel { direction: dir, squares: squares });
+      }
+    }
+  }
+  result
+}
+
+/// The squares the man can walk to from (`row`,`col`) without setting foot
+/// on a square marked in `seen`. They are marked as they are found.
+fn flood(fields: &[Vec<AnnotatedField>], seen: &mut [Vec<bool>], row: uint, col: uint) -> Vec<(uint, uint)> {
+  let mut result = Vec::new();
+  let mut todo = vec!((row, col));
+  *seen[row].get_mut(col) = true;
+  while !todo.is_empty() {
+    let (row, col) = todo.pop().unwrap();
+    result.push((row, col));
+    for &dir in DIRECTIONS.iter() {
+      let (dr, dc) = delta(dir);
+      let (r, c) = (row+dr, col+dc);
+      if valid_index(r, c, fields) && fields[r][c].reachable && !seen[r][c] {
+        *seen[r].get_mut(c) = true;
+        todo.push((r, c));
+      }
+    }
+  }
+  result
+}
+
+/// Finds the goal rooms by taking away each square in turn, and looking for
+/// a part of the board that gets cut off from the man that way. A room found
+/// inside another room is merged into it, so only the largest rooms are
+/// kept; filling the outer room fills the rooms inside it as well.
+fn find_goal_rooms(fields: &[Vec<AnnotatedField>]) -> Vec<GoalRoom> {
+  let mut rooms = Vec::new();
+  for row in fields.iter() {
+    for entrance in row.iter() {
+      if !entrance.reachable || entrance.isGoal() || entrance.hasBlock() || entrance.hasMan() {
+        continue;
+      }
+      let mut seen: Vec<Vec<bool>> = fields.iter().map(|row| Vec::from_elem(row.len(), false)).collect();
+      *seen.get_mut(entrance.row).get_mut(entrance.col) = true;
+      for &dir in DIRECTIONS.iter() {
+        let (dr, dc) = delta(dir);
+        let (r, c) = (entrance.row+dr, entrance.col+dc);
+        if !valid_index(r, c, fields) || !fields[r][c].reachable || seen[r][c] {
+          continue;
+        }
+        let squares = flood(fields, seen.as_mut_slice(), r, c);
+        if squares.iter().any(|&(r, c)| fields[r][c].isGoal())
+            && !squares.iter().any(|&(r, c)| fields[r][c].hasBlock() || fields[r][c].hasMan()) {
+          rooms.push(GoalRoom { entrance: (entrance.row, entrance.col), squares: squares });
+        }
+      }
+    }
+  }
+  let mut keep = Vec::new();
+  for (i, room) in rooms.iter().enumerate() {
+    let inside = |other: &GoalRoom| other.squares.len() > room.squares.len()
+        && room.squares.iter().all(|square| other.squares.contains(square));
+    if !rooms.iter().any(|other| inside(other)) {
+      keep.push(i);
+    }
+  }
+  rooms.move_iter().enumerate().filter(|&(i, _)| keep.contains(&i)).map(|(_, room)| room).collect()
+}
+
+/// Checks that the man is walled in and that there are as many blocks as
+/// goals, and rules out puzzles with a block on an unproductive spot or a
+/// frozen block that is not on a goal.
+fn sanityCheck(soko: &SokoAnnotatedBoard) -> Result<(), SolveError> {
+  let (mut blocks, mut goals) = (0u, 0u);
+  let lastRow = soko.board.len() - 1;
+  for row in soko.board.iter() {
+    let lastCol = row.len() - 1;
+    for field in row.iter() {
+      if field.reachable && (field.row == 0 || field.row == lastRow || field.col == 0 || field.col == lastCol) {
+        return Err(InvalidBoard(StrBuf::from_owned_str(
+            format!("The man can leave the board at [{},{}]",field.row,field.col))));
+      }
+      if field.hasBlock() {
+        blocks += 1;
+      }
+      if field.isGoal() {
+        goals += 1;
+      }
+    }
+  }
+  if blocks != goals {
+    return Err(InvalidBoard(StrBuf::from_owned_str(
+        format!("There are {} blocks but {} goals",blocks,goals))));
+  }
   for row in soko.board.iter() {
     for field in row.iter() {
       if !field.productive && field.hasBlock() {
-        panic!(format!("Impossible puzzle: Block on unproductive spot: [{},{}]",field.row,field.col))
+        // Impossible puzzle: Block on unproductive spot
+        return Err(Unsolvable(Stats::new()));
       }
     }
   }
+  let mut blocks = Vec::new();
+  for row in soko.board.iter() {
+    for field in row.iter() {
+      if field.hasBlock() {
+        blocks.push((field.row, field.col));
+      }
+    }
+  }
+  if soko.isFreezeDeadlock(blocks.as_slice()) {
+    return Err(Unsolvable(Stats::new()));
+  }
+  Ok(())
 }
 
 fn assignIDs(soko: &mut SokoAnnotatedBoard) {
@@ -197,19 +417,19 @@ fn man_var_at(fields: &[Vec<AnnotatedField>], row: uint, col: uint) -> u32 {
   fields[row][col].man_id.expect("Field without man_id!")
 }
 
-fn to_bdd_init(fields: &[Vec<AnnotatedField>]) -> Bdd {
-  let mut result = Bdd::bddTrue();
+fn to_bdd_init<'s>(syl: &'s Sylvan, fields: &[Vec<AnnotatedField>]) -> Bdd<'s> {
+  let mut result = syl.bddTrue();
   for row in fields.iter() {
     for field in row.iter() {
       if field.reachable {
-        let man = Bdd::fromId(man_var_at(fields, field.row, field.col));
+        let man = syl.fromId(man_var_at(fields, field.row, field.col));
         if field.hasMan() {
           result = result & man;
         } else {
           result = result & !man;
         }
         if field.productive {
-          let block = Bdd::fromId(block_var_at(fields, field.row, field.col));
+          let block = syl.fromId(block_var_at(fields, field.row, field.col));
           if field.hasBlock() {
             result = result & block;
           } else {
@@ -222,157 +442,567 @@ fn to_bdd_init(fields: &[Vec<AnnotatedField>]) -> Bdd {
   result
 }
 
-fn same_man(fields: &[Vec<AnnotatedField>], row: uint, col: uint) -> Bdd {
+fn same_man<'s>(syl: &'s Sylvan, fields: &[Vec<AnnotatedField>], row: uint, col: uint) -> Bdd<'s> {
   let mva = man_var_at(fields,row,col);
-  Bdd::fromId(mva).biimp( Bdd::fromId(mva+1) )
+  syl.fromId(mva).biimp( &syl.fromId(mva+1) )
 }
 
-fn same_block(fields: &[Vec<AnnotatedField>], row: uint, col: uint) -> Bdd {
+fn same_block<'s>(syl: &'s Sylvan, fields: &[Vec<AnnotatedField>], row: uint, col: uint) -> Bdd<'s> {
   let bva = block_var_at(fields,row,col);
-  Bdd::fromId(bva).biimp( Bdd::fromId(bva+1) )
-}
-
-fn everything_else_same(fields: &[Vec<AnnotatedField>], except: &[(uint, uint)]) -> Bdd {
-  let mut result = Bdd::bddTrue();
-  let mut excepts = 0;
-  for row in range(0,fields.len()) {
-    for col in range(0, fields[row].len()) {
-      if !except.contains(&(row,col)) {
-        if fields[row][col].reachable {
-          result = result & same_man(fields, row, col);
-          if fields[row][col].productive {
-            result = result & same_block(fields, row, col);
-          }
-        }
-      } else {
-        excepts += 1;
+  syl.fromId(bva).biimp( &syl.fromId(bva+1) )
+}
+
+/// The current and next-state variables of the squares in `cells`.
+fn vars_of<'s>(syl: &'s Sylvan, fields: &[Vec<AnnotatedField>], cells: &[(uint, uint)]) -> Bdd<'s> {
+  let mut result = syl.bddFalse();
+  for &(row,col) in cells.iter() {
+    if fields[row][col].reachable {
+      let mva = man_var_at(fields, row, col);
+      result = result | syl.fromId(mva) | syl.fromId(mva+1);
+      if fields[row][col].productive {
+        let bva = block_var_at(fields, row, col);
+        result = result | syl.fromId(bva) | syl.fromId(bva+1);
+      }
+    }
+  }
+  result
+}
+
+/// Relates the current and next-state variables of the squares in `cells`.
+fn same_of<'s>(syl: &'s Sylvan, fields: &[Vec<AnnotatedField>], cells: &[(uint, uint)]) -> Bdd<'s> {
+  let mut result = syl.bddTrue();
+  for &(row,col) in cells.iter() {
+    if fields[row][col].reachable {
+      result = result & same_man(syl, fields, row, col);
+      if fields[row][col].productive {
+        result = result & same_block(syl, fields, row, col);
       }
     }
   }
-  assert_eq!(excepts, except.len());
   result
 }
 
-fn to_bdd_transitions(row: uint, col: uint, dr: uint, dc: uint, fields: &[Vec<AnnotatedField>]) -> Bdd {
-  if valid_index(row,col,fields)
+static DIRECTIONS: [Direction, ..4] = [Up, Down, Left, Right];
+
+/// The row and column offsets of a step in direction `dir`.
+fn delta(dir: Direction) -> (uint, uint) {
+  match dir {
+    Up => (-1, 0),
+    Down => (1, 0),
+    Left => (0, -1),
+    Right => (0, 1),
+  }
+}
+
+#[derive(Eq)]
+enum StepKind {
+  Walk,
+  Push,
+}
+
+/// One part of the transition relation: the man walking or pushing from a
+/// single square in a single direction, or a macro of several moves.
+/// `moves` are the moves the man makes. `relation` only mentions the squares
+/// involved; `vars` holds their current and next-state variables and
+/// `equalizer` relates those pairs, so every other square keeps its value
+/// when the partition is applied.
+struct Partition<'s> {
+  moves: Vec<Move>,
+  kind: StepKind,
+  relation: Bdd<'s>,
+  vars: Bdd<'s>,
+  equalizer: Bdd<'s>,
+}
+
+impl<'s> Partition<'s> {
+  fn new(syl: &'s Sylvan, fields: &[Vec<AnnotatedField>], direction: Direction, kind: StepKind,
+         relation: Bdd<'s>, cells: &[(uint, uint)]) -> Partition<'s> {
+    Partition::with_moves(syl, fields, vec!(Move { direction: direction, push: kind == Push }), kind, relation, cells)
+  }
+
+  fn with_moves(syl: &'s Sylvan, fields: &[Vec<AnnotatedField>], moves: Vec<Move>, kind: StepKind,
+                relation: Bdd<'s>, cells: &[(uint, uint)]) -> Partition<'s> {
+    Partition {
+      moves: moves,
+      kind: kind,
+      relation: relation,
+      vars: vars_of(syl, fields, cells),
+      equalizer: same_of(syl, fields, cells),
+    }
+  }
+
+  /// Adds the moves of this step to `path`, which is built back to front.
+  fn prepend_to(&self, path: &mut Vec<Move>) {
+    for m in self.moves.iter().rev() {
+      path.push(m.clone());
+    }
+  }
+
+  /// The states reachable from `set` by this step.
+  fn image(&self, set: &Bdd<'s>) -> Bdd<'s> {
+    set.relprods_equalize(&self.relation, &self.vars, &self.equalizer)
+  }
+
+  /// The states from which this step leads into `set`.
+  fn preimage(&self, set: &Bdd<'s>) -> Bdd<'s> {
+    set.relprods(&self.equalizer, &self.vars).relprods_reversed(&self.relation, &self.vars)
+  }
+}
+
+/// The states reachable from `set` in one step of any partition.
+fn image<'s>(syl: &'s Sylvan, partitions: &[Partition<'s>], set: &Bdd<'s>) -> Bdd<'s> {
+  let mut result = syl.bddFalse();
+  for partition in partitions.iter() {
+    result = result | partition.image(set);
+  }
+  result
+}
+
+/// Like `image`, but checks the limits before each partition, so that a time
+/// limit or a cancellation can end a long layer.
+fn image_within<'s>(syl: &'s Sylvan, partitions: &[Partition<'s>], set: &Bdd<'s>,
+                    start: u64, limits: &Limits, stats: &mut Stats) -> Result<Bdd<'s>, SolveError> {
+  let mut result = syl.bddFalse();
+  for partition in partitions.iter() {
+    try!(check_limits(start, limits, stats));
+    result = result | partition.image(set);
+  }
+  Ok(result)
+}
+
+/// The states from which one step of any partition leads into `set`. The
+/// limits are checked as in `image_within`.
+fn preimage_within<'s>(syl: &'s Sylvan, partitions: &[Partition<'s>], set: &Bdd<'s>,
+                       start: u64, limits: &Limits, stats: &mut Stats) -> Result<Bdd<'s>, SolveError> {
+  let mut result = syl.bddFalse();
+  for partition in partitions.iter() {
+    try!(check_limits(start, limits, stats));
+    result = result | partition.preimage(set);
+  }
+  Ok(result)
+}
+
+/// The man walks from (`row`,`col`) to the empty square next to it.
+fn walk_partition<'s>(syl: &'s Sylvan, fields: &[Vec<AnnotatedField>], row: uint, col: uint, dir: Direction) -> Option<Partition<'s>> {
+  let (dr, dc) = delta(dir);
+  if !(valid_index(row,col,fields)
       && fields[row][col].reachable
       && valid_index(row+dr,col+dc,fields)
-      && fields[row+dr][col+dc].reachable {
-    let mva0 = man_var_at(fields,row,col);
-    let current_man = Bdd::fromId(mva0);
-    let next_man = Bdd::fromId(mva0+1);
+      && fields[row+dr][col+dc].reachable) {
+    return None;
+  }
+  let mva0 = man_var_at(fields,row,col);
+  let current_man = syl.fromId(mva0);
+  let next_man = syl.fromId(mva0+1);
 
-    let mva1 = man_var_at(fields,row+dr,col+dc);
-    let current_man_dxdy = Bdd::fromId(mva1);
-    let next_man_dxdy = Bdd::fromId(mva1+1);
+  let mva1 = man_var_at(fields,row+dr,col+dc);
+  let current_man_dxdy = syl.fromId(mva1);
+  let next_man_dxdy = syl.fromId(mva1+1);
 
-    let bva0 = block_var_at(fields,row,col);
-    let current_block = Bdd::fromId(bva0);
-    let next_block = Bdd::fromId(bva0+1);
+  let man_moves = current_man & (!current_man_dxdy) & (!next_man) & next_man_dxdy;
 
+  let mut no_block = syl.bddTrue();
+  if fields[row][col].productive {
+    let bva0 = block_var_at(fields,row,col);
+    no_block = no_block & !syl.fromId(bva0) & !syl.fromId(bva0+1);
+  }
+  if fields[row+dr][col+dc].productive {
     let bva1 = block_var_at(fields,row+dr,col+dc);
-    let current_block_dxdy = Bdd::fromId(bva1);
-    let next_block_dxdy = Bdd::fromId(bva1+1);
-
-    let man_moves = current_man & (!current_man_dxdy) & (!next_man) & next_man_dxdy;
+    no_block = no_block & !syl.fromId(bva1) & !syl.fromId(bva1+1);
+  }
 
-    let no_block = if fields[row][col].productive && fields[row+dr][col+dc].productive {
-      (!current_block) & (!current_block_dxdy) & (!next_block) & (!next_block_dxdy)
-    } else if fields[row][col].productive {
-      (!current_block) & (!next_block)
-    } else if fields[row+dr][col+dc].productive {
-      (!current_block_dxdy) & (!next_block_dxdy)
-    } else {
-      Bdd::bddTrue()
-    };
+  let cells = [(row,col), (row+dr,col+dc)];
+  Some(Partition::new(syl, fields, dir, Walk, man_moves & no_block, cells.as_slice()))
+}
 
-    let here = (row, col);
-    let there = (row+dr, col+dc);
-    let mut result = Bdd::bddFalse();
-
-    if valid_index(row+dr, col+dc, fields) && fields[row+dr][col+dc].reachable {
-      let everything_same_2 = everything_else_same(fields, [here,there].as_slice());
-      result = result | ( man_moves & no_block & everything_same_2);
-      let (rrr,ccc) = (row+dr+dr,col+dc+dc);
-      if valid_index(rrr,ccc,fields) && fields[rrr][ccc].productive {
-        let mva2 = man_var_at(fields,rrr,ccc);
-        let current_man_2dxdy = Bdd::fromId(mva2);
-        let next_man_2dxdy = Bdd::fromId(mva2+1);
-        let bva2 = block_var_at(fields,rrr,ccc);
-        let current_block_2dxdy = Bdd::fromId(bva2);
-        let next_block_2dxdy = Bdd::fromId(bva2+1);
-        let block_moves = current_block_dxdy & (!current_block_2dxdy) & (!next_block_dxdy) & next_block_2dxdy;
-
-        let everything_same_3 = everything_else_same(fields, [here,there,(rrr,ccc)].as_slice());
-        let man_2dxdy_same = (!current_man_2dxdy).biimp(!next_man_2dxdy);
-        let not_block_present = (!current_block).biimp(!next_block);
-        result = result | (man_moves & not_block_present & block_moves & man_2dxdy_same & everything_same_3);
+/// The positions in which the block just pushed from `from` to `to` fills a
+/// 2x2 square of walls and blocks, one of them not on a goal. None of those
+/// blocks can ever be moved again. The other blocks in the square are not
+/// moved by the push, so their current-state variables are used. This is
+/// only the simplest case of the rule `isFreezeDeadlock` checks: chains of
+/// frozen blocks and blocks stuck between dead squares are not covered.
+fn frozen_2x2<'s>(syl: &'s Sylvan, fields: &[Vec<AnnotatedField>], from: (uint, uint), to: (uint, uint)) -> Bdd<'s> {
+  let (row, col) = to;
+  let mut result = syl.bddFalse();
+  for &(dr, dc) in [(0u, 0u), (0, -1), (-1, 0), (-1, -1)].iter() {
+    let (top, left) = (row+dr, col+dc);
+    let square = [(top, left), (top, left+1), (top+1, left), (top+1, left+1)];
+    if square.iter().any(|&(r, c)| !valid_index(r, c, fields)) || square.contains(&from) {
+      continue;
+    }
+    let mut full = syl.bddTrue();
+    let mut possible = true;
+    let mut off_goal = !fields[row][col].isGoal();
+    for &(r, c) in square.iter() {
+      if (r, c) == to || fields[r][c].field == Wall {
+        continue;
       }
+      if !(fields[r][c].reachable && fields[r][c].productive) {
+        possible = false;
+        break;
+      }
+      full = full & syl.fromId(block_var_at(fields, r, c));
+      off_goal = off_goal || !fields[r][c].isGoal();
     }
-    result
+    if possible && off_goal {
+      result = result | full;
+    }
+  }
+  result
+}
+
+/// The man at (`row`,`col`) pushes the block next to him one square further.
+/// With `freeze` set, pushes that fill a 2x2 square of walls and blocks,
+/// one of them off its goal, are left out.
+fn push_partition<'s>(syl: &'s Sylvan, fields: &[Vec<AnnotatedField>], row: uint, col: uint, dir: Direction,
+                  freeze: bool) -> Option<Partition<'s>> {
+  let (dr, dc) = delta(dir);
+  let (rrr,ccc) = (row+dr+dr,col+dc+dc);
+  if !(valid_index(row,col,fields)
+      && fields[row][col].reachable
+      && valid_index(row+dr,col+dc,fields)
+      && fields[row+dr][col+dc].productive
+      && valid_index(rrr,ccc,fields)
+      && fields[rrr][ccc].productive) {
+    return None;
+  }
+  let mva0 = man_var_at(fields,row,col);
+  let current_man = syl.fromId(mva0);
+  let next_man = syl.fromId(mva0+1);
+
+  let mva1 = man_var_at(fields,row+dr,col+dc);
+  let current_man_dxdy = syl.fromId(mva1);
+  let next_man_dxdy = syl.fromId(mva1+1);
+
+  let mva2 = man_var_at(fields,rrr,ccc);
+  let current_man_2dxdy = syl.fromId(mva2);
+  let next_man_2dxdy = syl.fromId(mva2+1);
+
+  let bva1 = block_var_at(fields,row+dr,col+dc);
+  let current_block_dxdy = syl.fromId(bva1);
+  let next_block_dxdy = syl.fromId(bva1+1);
+
+  let bva2 = block_var_at(fields,rrr,ccc);
+  let current_block_2dxdy = syl.fromId(bva2);
+  let next_block_2dxdy = syl.fromId(bva2+1);
+
+  let man_moves = current_man & (!current_man_dxdy) & (!next_man) & next_man_dxdy;
+  let block_moves = current_block_dxdy & (!current_block_2dxdy) & (!next_block_dxdy) & next_block_2dxdy;
+  let man_2dxdy_same = (!current_man_2dxdy).biimp(&!next_man_2dxdy);
+  let not_block_present = if fields[row][col].productive {
+    let bva0 = block_var_at(fields,row,col);
+    (!syl.fromId(bva0)) & (!syl.fromId(bva0+1))
   } else {
-    Bdd::bddFalse()
+    syl.bddTrue()
+  };
+
+  let cells = [(row,col), (row+dr,col+dc), (rrr,ccc)];
+  let mut relation = man_moves & not_block_present & block_moves & man_2dxdy_same;
+  if freeze {
+    relation = relation & !frozen_2x2(syl, fields, (row+dr,col+dc), (rrr,ccc));
   }
+  Some(Partition::new(syl, fields, dir, Push, relation, cells.as_slice()))
 }
 
-fn to_bdd_trans_direction(fields: &[Vec<AnnotatedField>], drow: uint, dcol: uint) -> Bdd {
-  let mut result = Bdd::bddFalse();
-  for row in fields.iter() {
-    for field in row.iter() {
-      if field.reachable {
-        result = result | to_bdd_transitions(field.row, field.col, drow, dcol, fields);
-      }
+/// The man walks from `man` to `man_after`, while the blocks on the squares
+/// in `cells` go from `blocks` to `blocks_after`. Every variable of `cells`
+/// is fixed, before and after.
+fn exact_step<'s>(syl: &'s Sylvan, fields: &[Vec<AnnotatedField>], cells: &[(uint, uint)], man: (uint, uint),
+              man_after: (uint, uint), blocks: &[(uint, uint)], blocks_after: &[(uint, uint)]) -> Bdd<'s> {
+  let literal = |id: u32, set: bool| -> Bdd<'s> { if set { syl.fromId(id) } else { !syl.fromId(id) } };
+  let mut result = syl.bddTrue();
+  for &(row,col) in cells.iter() {
+    if !fields[row][col].reachable {
+      continue;
+    }
+    let mva = man_var_at(fields, row, col);
+    result = result & literal(mva, (row,col) == man) & literal(mva+1, (row,col) == man_after);
+    if fields[row][col].productive {
+      let bva = block_var_at(fields, row, col);
+      result = result & literal(bva, blocks.contains(&(row,col))) & literal(bva+1, blocks_after.contains(&(row,col)));
     }
   }
   result
 }
 
-fn to_bdd_trans_up(fields: &[Vec<AnnotatedField>]) -> Bdd {
-  to_bdd_trans_direction(fields, -1, 0)
+/// The man pushes the block in front of `tunnel` all the way through it in
+/// direction `dir`, onto the square beyond. `freeze` is as for
+/// `push_partition`.
+fn tunnel_partition<'s>(syl: &'s Sylvan, fields: &[Vec<AnnotatedField>], tunnel: &Tunnel, dir: Direction,
+                        freeze: bool) -> Option<Partition<'s>> {
+  let (dr, dc) = delta(dir);
+  let mut squares = tunnel.squares.clone();
+  if dir != tunnel.direction {
+    squares.reverse();
+  }
+  let (first_row, first_col) = squares[0];
+  let (last_row, last_col) = *squares.last().unwrap();
+  let (br, bc) = (first_row-dr, first_col-dc);
+  let (mr, mc) = (br-dr, bc-dc);
+  let (er, ec) = (last_row+dr, last_col+dc);
+  if !(valid_index(mr,mc,fields)
+      && fields[mr][mc].reachable
+      && valid_index(br,bc,fields)
+      && fields[br][bc].productive
+      && valid_index(er,ec,fields)
+      && fields[er][ec].productive) {
+    return None;
+  }
+  let mut cells = vec!((mr,mc), (br,bc));
+  cells.push_all(squares.as_slice());
+  cells.push((er,ec));
+  let mut relation = exact_step(syl, fields, cells.as_slice(), (mr,mc), (last_row,last_col), &[(br,bc)], &[(er,ec)]);
+  if freeze {
+    relation = relation & !frozen_2x2(syl, fields, (br,bc), (er,ec));
+  }
+  let moves = Vec::from_elem(squares.len() + 1, Move { direction: dir, push: true });
+  Some(Partition::with_moves(syl, fields, moves, Push, relation, cells.as_slice()))
 }
 
-fn to_bdd_trans_down(fields: &[Vec<AnnotatedField>]) -> Bdd {
-  to_bdd_trans_direction(fields, 1, 0)
+/// The shortest way to push a block somewhere, and where the man ends up.
+struct PushPath {
+  moves: Vec<Move>,
+  man: (uint, uint),
 }
 
-fn to_bdd_trans_left(fields: &[Vec<AnnotatedField>]) -> Bdd {
-  to_bdd_trans_direction(fields, 0, -1)
+/// Finds the shortest ways, in moves, for the man on `man` to push the block
+/// on `block` to each square it can get to, keeping both of them on the
+/// squares marked in `area` and off the squares in `filled`.
+fn push_paths(fields: &[Vec<AnnotatedField>], area: &[Vec<bool>], filled: &[(uint, uint)],
+              block: (uint, uint), man: (uint, uint)) -> HashMap<(uint, uint), PushPath> {
+  let free = |(row, col): (uint, uint)| valid_index(row, col, area) && area[row][col] && !filled.contains(&(row, col));
+  let start = (block, man);
+  let mut parents = HashMap::new();
+  let mut seen = HashSet::new();
+  let mut queue = vec!(start);
+  let mut head = 0;
+  let mut result = HashMap::new();
+  seen.insert(start);
+  while head < queue.len() {
+    let state = queue[head];
+    head += 1;
+    let ((br, bc), (mr, mc)) = state;
+    if !result.contains_key(&(br, bc)) {
+      let mut moves = Vec::new();
+      let mut current = state;
+      while current != start {
+        let &(previous, ref step) = parents.find(&current).unwrap();
+        moves.push(step.clone());
+        current = previous;
+      }
+      moves.reverse();
+      result.insert((br, bc), PushPath { moves: moves, man: (mr, mc) });
+    }
+    for &dir in DIRECTIONS.iter() {
+      let (dr, dc) = delta(dir);
+      let there = (mr+dr, mc+dc);
+      if !free(there) {
+        continue;
+      }
+      let (next, push) = if there == (br, bc) {
+        let (r, c) = (br+dr, bc+dc);
+        if !free((r, c)) || !fields[r][c].productive {
+          continue;
+        }
+        (((r, c), there), true)
+      } else {
+        (((br, bc), there), false)
+      };
+      if seen.insert(next) {
+        parents.insert(next, (state, Move { direction: dir, push: push }));
+        queue.push(next);
+      }
+    }
+  }
+  result
 }
 
-fn to_bdd_trans_right(fields: &[Vec<AnnotatedField>]) -> Bdd {
-  to_bdd_trans_direction(fields, 0, 1)
+/// The man pushes a block through the entrance of `room` onto one of its
+/// goals, from each side of the entrance he can push from. The goals are
+/// filled one at a time, the one farthest from the entrance first, so the
+/// blocks already in place stay out of the way. Each partition fills the
+/// next goal in that order, and only applies when the goals before it, and
+/// no other squares of the room, hold blocks. `freeze` is as for
+/// `push_partition`.
+fn goal_room_partitions<'s>(syl: &'s Sylvan, fields: &[Vec<AnnotatedField>], room: &GoalRoom,
+                            freeze: bool) -> Vec<Partition<'s>> {
+  let mut result = Vec::new();
+  let (er, ec) = room.entrance;
+  let mut inside: Vec<Vec<bool>> = fields.iter().map(|row| Vec::from_elem(row.len(), false)).collect();
+  for &(row, col) in room.squares.iter() {
+    *inside.get_mut(row).get_mut(col) = true;
+  }
+  let goals: Vec<(uint, uint)> = room.squares.iter().map(|&square| square).filter(|&(row, col)| fields[row][col].isGoal()).collect();
+  for &dir in DIRECTIONS.iter() {
+    let (dr, dc) = delta(dir);
+    let (br, bc) = (er-dr, ec-dc);
+    let (mr, mc) = (br-dr, bc-dc);
+    if !(valid_index(mr,mc,fields)
+        && fields[mr][mc].reachable
+        && !inside[mr][mc]
+        && valid_index(br,bc,fields)
+        && fields[br][bc].productive
+        && !inside[br][bc]) {
+      continue;
+    }
+    let mut area = inside.clone();
+    let mut cells = room.squares.clone();
+    for &(row, col) in [(er,ec), (br,bc), (mr,mc)].iter() {
+      *area.get_mut(row).get_mut(col) = true;
+      cells.push((row, col));
+    }
+    let mut filled = Vec::new();
+    loop {
+      let paths = push_paths(fields, area.as_slice(), filled.as_slice(), (br,bc), (mr,mc));
+      let goal = match goals.iter().filter(|&goal| !filled.contains(goal) && paths.contains_key(goal))
+                                   .max_by(|&goal| paths.find(&goal).unwrap().moves.len()) {
+        Some(&goal) => goal,
+        None => break,
+      };
+      let path = paths.find(&goal).unwrap();
+      let mut before = filled.clone();
+      before.push((br,bc));
+      filled.push(goal);
+      let mut relation = exact_step(syl, fields, cells.as_slice(), (mr,mc), path.man, before.as_slice(), filled.as_slice());
+      if freeze {
+        relation = relation & !frozen_2x2(syl, fields, (br,bc), goal);
+      }
+      result.push(Partition::with_moves(syl, fields, path.moves.clone(), Push, relation, cells.as_slice()));
+    }
+  }
+  result
 }
 
-fn to_bdd_goal(fields: &[Vec<AnnotatedField>]) -> Bdd {
-  let mut result = Bdd::bddTrue();
+/// The transition relation, as one partition per square, direction and kind
+/// of step. With `freeze` set, pushes that freeze blocks in a 2x2 square are
+/// left out; that loses no solutions, but does leave out reachable
+/// positions. With
+/// `macros` set, pushing a block through a tunnel or into a goal room is
+/// also a single step.
+fn to_bdd_partitions<'s>(syl: &'s Sylvan, fields: &[Vec<AnnotatedField>], freeze: bool, macros: bool) -> Vec<Partition<'s>> {
+  let mut result = Vec::new();
+  for row in fields.iter() {
+    for field in row.iter() {
+      if field.reachable {
+        for &dir in DIRECTIONS.iter() {
+          for partition in walk_partition(syl, fields, field.row, field.col, dir).move_iter() {
+            result.push(partition);
+          }
+          for partition in push_partition(syl, fields, field.row, field.col, dir, freeze).move_iter() {
+            result.push(partition);
+          }
+        }
+      }
+    }
+  }
+  if macros {
+    for tunnel in find_tunnels(fields).iter() {
+      for &dir in [tunnel.direction, tunnel.direction.opposite()].iter() {
+        for partition in tunnel_partition(syl, fields, tunnel, dir, freeze).move_iter() {
+          result.push(partition);
+        }
+      }
+    }
+    for room in find_goal_rooms(fields).iter() {
+      result.push_all_move(goal_room_partitions(syl, fields, room, freeze));
+    }
+  }
+  result
+}
+
+/// The positions in which none of `patterns` is found anywhere on the board
+/// with one of its stuck blocks off a goal.
+fn to_bdd_patterns<'s>(syl: &'s Sylvan, fields: &[Vec<AnnotatedField>], patterns: &Patterns) -> Bdd<'s> {
+  let mut deadlocked = syl.bddFalse();
+  let (height, width) = (fields.len(), fields[0].len());
+  for top in range(0, height) {
+    for left in range(0, width) {
+      if top + SIZE > height || left + SIZE > width {
+        continue;
+      }
+      let square = |i: uint| (top + i / SIZE, left + i % SIZE);
+      let mut walls = 0u;
+      for i in range(0, SIZE * SIZE) {
+        let (row, col) = square(i);
+        if fields[row][col].field == Wall {
+          walls |= 1 << i;
+        }
+      }
+      for pattern in patterns.with_walls(walls).iter() {
+        let mut matched = syl.bddTrue();
+        let mut possible = true;
+        let mut off_goal = false;
+        for i in range(0, SIZE * SIZE) {
+          let (row, col) = square(i);
+          let field = &fields[row][col];
+          let has_block_var = field.reachable && field.productive;
+          if pattern.boxes & (1 << i) != 0 {
+            if !has_block_var {
+              possible = false;
+              break;
+            }
+            matched = matched & syl.fromId(block_var_at(fields, row, col));
+            off_goal = off_goal || (pattern.stuck & (1 << i) != 0 && !field.isGoal());
+          } else if walls & (1 << i) == 0 && has_block_var {
+            matched = matched & !syl.fromId(block_var_at(fields, row, col));
+          }
+        }
+        if possible && off_goal {
+          deadlocked = deadlocked | matched;
+        }
+      }
+    }
+  }
+  !deadlocked
+}
+
+fn to_bdd_goal<'s>(syl: &'s Sylvan, fields: &[Vec<AnnotatedField>]) -> Bdd<'s> {
+  let mut result = syl.bddTrue();
   for row in fields.iter() {
     for field in row.iter() {
       if field.isGoal() {
-        result = result & Bdd::fromId(block_var_at(fields, field.row, field.col));
+        result = result & syl.fromId(block_var_at(fields, field.row, field.col));
       }
     }
   }
   result
 }
 
-fn to_bdd_trans(fields: &[Vec<AnnotatedField>]) -> Bdd {
-  let up = to_bdd_trans_up(fields);
-  let down = to_bdd_trans_down(fields);
-  let left = to_bdd_trans_left(fields);
-  let right = to_bdd_trans_right(fields);
-  up | down | left | right
+/// Every solved position: all blocks on goals and the man on any free square
+/// he can reach.
+fn to_bdd_solved<'s>(syl: &'s Sylvan, fields: &[Vec<AnnotatedField>]) -> Bdd<'s> {
+  let mut result = syl.bddTrue();
+  let mut no_man = syl.bddTrue();
+  let mut one_man = syl.bddFalse();
+  for row in fields.iter() {
+    for field in row.iter() {
+      if field.reachable {
+        let man = syl.fromId(man_var_at(fields, field.row, field.col));
+        if field.productive {
+          let block = syl.fromId(block_var_at(fields, field.row, field.col));
+          if field.isGoal() {
+            result = result & block & !man;
+          } else {
+            result = result & !block;
+          }
+        }
+        one_man = (one_man & !man) | (no_man & man);
+        no_man = no_man & !man;
+      }
+    }
+  }
+  result & one_man
 }
 
-fn all_vars(fields: &[Vec<AnnotatedField>]) -> Bdd {
-  let mut result = Bdd::bddFalse();
+/// The set of current-state variables.
+fn current_vars<'s>(syl: &'s Sylvan, fields: &[Vec<AnnotatedField>]) -> Bdd<'s> {
+  let mut result = syl.bddFalse();
   for row in fields.iter() {
     for field in row.iter() {
       if field.reachable {
-        let mva = man_var_at(fields, field.row, field.col);
-        result = result | Bdd::fromId(mva) | Bdd::fromId(mva+1);
+        result = result | syl.fromId(man_var_at(fields, field.row, field.col));
         if field.productive {
-          let bva = block_var_at(fields, field.row, field.col);
-          result = result | Bdd::fromId(bva) | Bdd::fromId(bva+1);
+          result = result | syl.fromId(block_var_at(fields, field.row, field.col));
         }
       }
     }
@@ -380,96 +1010,622 @@ fn all_vars(fields: &[Vec<AnnotatedField>]) -> Bdd {
   result
 }
 
-fn reconstruct_path(visited: Vec<Bdd>, goal: Bdd, equalizer: Bdd, fields: &[Vec<AnnotatedField>]) {
-  use super::std::strbuf::StrBuf;
-  let allvars = all_vars(fields);
+/// Finds a step among `partitions` from a state in `layer` into `current`,
+/// and returns it together with the states it starts from.
+fn step_back<'a, 's>(syl: &'s Sylvan, layer: &Bdd<'s>, partitions: &'a [Partition<'s>], current: &Bdd<'s>) -> (&'a Partition<'s>, Bdd<'s>) {
+  let bddFalse = syl.bddFalse();
+  for partition in partitions.iter() {
+    let previous = *layer & partition.preimage(current);
+    if previous != bddFalse {
+      return (partition, previous);
+    }
+  }
+  panic!("Backtracking error");
+}
+
+/// Finds a step among `partitions` from `current` into a state in `layer`,
+/// and returns it together with the states it leads to.
+fn step_forward<'a, 's>(syl: &'s Sylvan, layer: &Bdd<'s>, partitions: &'a [Partition<'s>], current: &Bdd<'s>) -> (&'a Partition<'s>, Bdd<'s>) {
+  let bddFalse = syl.bddFalse();
+  for partition in partitions.iter() {
+    let next = *layer & partition.image(current);
+    if next != bddFalse {
+      return (partition, next);
+    }
+  }
+  panic!("Backtracking error");
+}
+
+/// Walks back from the goal through the breadth-first `layers`, choosing at
+/// each layer a step that leads to a state in the previous one.
+fn reconstruct_path<'s>(syl: &'s Sylvan, layers: Vec<Bdd<'s>>, goal: Bdd<'s>, partitions: &[Partition<'s>]) -> Vec<Move> {
   let mut current = goal;
-  let trans_up = to_bdd_trans_up(fields);
-  let trans_down = to_bdd_trans_down(fields);
-  let trans_left = to_bdd_trans_left(fields);
-  let trans_right = to_bdd_trans_right(fields);
-  let mut path = StrBuf::with_capacity( visited.len() );
-
-  let bddFalse = Bdd::bddFalse();
-  for i in range(1, visited.len()).rev() {
-    current = current.relprods(equalizer,allvars);
-    let (up,down,left,right) = (
-      visited.get(i-1) & current.relprods_reversed(trans_up,allvars),
-      visited.get(i-1) & current.relprods_reversed(trans_down,allvars),
-      visited.get(i-1) & current.relprods_reversed(trans_left,allvars),
-      visited.get(i-1) & current.relprods_reversed(trans_right,allvars)
-    );
-    current = if up != bddFalse {
-      path.push_char('u');
-      up
-    } else if down != bddFalse {
-      path.push_char('d');
-      down
-    } else if left != bddFalse {
-      path.push_char('l');
-      left
-    } else if right != bddFalse {
-      path.push_char('r');
-      right
+  let mut path = Vec::with_capacity( layers.len() );
+
+  for i in range(1, layers.len()).rev() {
+    let (partition, previous) = step_back(syl, layers.get(i-1), partitions, &current);
+    partition.prepend_to(&mut path);
+    current = previous;
+  }
+  path.reverse();
+  path
+}
+
+/// Tells `progress` about `layer`, the layer just added to `stats`.
+fn report<'s>(progress: &mut Progress, start: u64, stats: &Stats, layer: &Bdd<'s>) {
+  progress.layer(&LayerReport {
+    layer: stats.layer_states.len() - 1,
+    states: *stats.layer_states.last().unwrap(),
+    nodes: layer.node_count(),
+    millis: (precise_time_ns() - start) / 1000000,
+  });
+}
+
+fn won<'s>(syl: &'s Sylvan, current: &Bdd<'s>, goal: &Bdd<'s>) -> bool {
+  *current & *goal != syl.bddFalse()
+}
+
+fn solve_the_puzzle<'s>(syl: &'s Sylvan, initial: Bdd<'s>, partitions: &[Partition<'s>], invariant: &Bdd<'s>, goal: Bdd<'s>,
+                    fields: &[Vec<AnnotatedField>], start: u64, limits: &Limits, progress: &mut Progress)
+                    -> Result<Solution, SolveError> {
+  use std::cmp::max;
+
+  let statevars = current_vars(syl, fields);
+  let bddFalse = syl.bddFalse();
+
+  // Only the states first found in the previous layer are imaged; anything
+  // already reached is removed from the new layer.
+  let mut frontier = initial;
+  let mut reached = frontier.clone();
+  let mut layers = vec!(frontier.clone());
+
+  let mut stats = Stats::new();
+  stats.largest_layer = frontier.node_count();
+  stats.layer_states.push(frontier.sat_count(&statevars));
+  while !won(syl, &frontier, &goal) {
+    try!(check_limits(start, limits, &mut stats));
+    let next = try!(image_within(syl, partitions, &frontier, start, limits, &mut stats)) & !reached & *invariant;
+    if next == bddFalse {
+      return Err(Unsolvable(stats));
+    }
+    reached = reached | next;
+    stats.iterations += 1;
+    stats.largest_layer = max(stats.largest_layer, max(next.node_count(), reached.node_count()));
+    stats.layer_states.push(next.sat_count(&statevars));
+    report(progress, start, &stats, &next);
+    layers.push(next.clone());
+    frontier = next;
+  }
+  let path = reconstruct_path(syl, layers, goal, partitions);
+  stats.millis = (precise_time_ns() - start) / 1000000;
+  Ok(Solution::new(path, stats))
+}
+
+/// Adds to `set` every state the man can walk to without pushing a block.
+fn walk_closure<'s>(syl: &'s Sylvan, walks: &[Partition<'s>], set: Bdd<'s>) -> Bdd<'s> {
+  let bddFalse = syl.bddFalse();
+  let mut result = set.clone();
+  let mut frontier = set;
+  loop {
+    let next = image(syl, walks, &frontier) & !result;
+    if next == bddFalse {
+      return result;
+    }
+    result = result | next;
+    frontier = next;
+  }
+}
+
+/// The man variables of the squares he can reach, lowest-indexed first.
+fn man_vars(fields: &[Vec<AnnotatedField>]) -> Vec<u32> {
+  let mut result = Vec::new();
+  for row in fields.iter() {
+    for field in row.iter() {
+      if field.reachable {
+        result.push(man_var_at(fields, field.row, field.col));
+      }
+    }
+  }
+  result
+}
+
+/// Keeps of the states in `closed`, which must be closed under walking, only
+/// those with the man on the lowest-indexed square of his region, so that
+/// every region is stored as a single state. `men` are the man variables in
+/// the order of `man_vars`.
+fn normalize_man<'s>(syl: &'s Sylvan, walks: &[Partition<'s>], men: &[u32], closed: &Bdd<'s>) -> Bdd<'s> {
+  let bddFalse = syl.bddFalse();
+  let mut result = bddFalse.clone();
+  let mut covered = bddFalse.clone();
+  for &var in men.iter() {
+    if covered == *closed {
+      break;
+    }
+    let lowest = *closed & syl.fromId(var) & !covered;
+    if lowest != bddFalse {
+      covered = covered | walk_closure(syl, walks, lowest.clone());
+      result = result | lowest;
+    }
+  }
+  result
+}
+
+/// Walks back from the goal through the push `layers`, which hold normalized
+/// states; each layer is expanded to the man's whole region again on the way.
+/// Within a layer the man walks back to where the push into it left him (or
+/// to his initial square), going through `rings` of increasing walking
+/// distance from that spot.
+fn reconstruct_push_path<'s>(syl: &'s Sylvan, layers: Vec<Bdd<'s>>, initial: Bdd<'s>, goal: Bdd<'s>,
+                             walks: &[Partition<'s>], pushes: &[Partition<'s>]) -> Vec<Move> {
+  let bddFalse = syl.bddFalse();
+  let mut closed = walk_closure(syl, walks, layers.last().unwrap().clone());
+  let mut current = closed & goal;
+  let mut path = Vec::new();
+
+  for i in range(0, layers.len()).rev() {
+    let previous_closed = if i > 0 {
+      walk_closure(syl, walks, layers.get(i-1).clone())
+    } else {
+      bddFalse.clone()
+    };
+    let pushed = if i > 0 {
+      image(syl, pushes, &previous_closed) & closed
     } else {
-      panic!("Backtracking error");
+      initial.clone()
     };
+    let mut rings = vec!(pushed.clone());
+    let mut seen = pushed;
+    while *rings.last().unwrap() & current == bddFalse {
+      let next = image(syl, walks, rings.last().unwrap()) & closed & !seen;
+      if next == bddFalse {
+        panic!("Backtracking error");
+      }
+      seen = seen | next;
+      rings.push(next);
+    }
+    current = current & *rings.last().unwrap();
+    for k in range(1, rings.len()).rev() {
+      let (partition, previous) = step_back(syl, rings.get(k-1), walks, &current);
+      partition.prepend_to(&mut path);
+      current = previous;
+    }
+    if i > 0 {
+      let (partition, previous) = step_back(syl, &previous_closed, pushes, &current);
+      partition.prepend_to(&mut path);
+      current = previous;
+    }
+    closed = previous_closed;
   }
-  println!("Solution: {}", path
-      .as_slice()
-      .chars_rev()
-      .fold(StrBuf::with_capacity( visited.len() ), |sb,c|{let mut sb = sb;sb.push_char(c);sb})
-      .as_slice());
+  path.reverse();
+  path
 }
 
-fn solve_the_puzzle(initial: Bdd, transitions: Bdd, goal: Bdd, fields: &[Vec<AnnotatedField>]) {
-  fn won(current: Bdd, goal: Bdd) -> bool {
-    current & goal != Bdd::bddFalse()
+/// Like `solve_the_puzzle`, but every layer consists of a walk followed by a
+/// single push, so the solution has as few pushes as possible. The states of
+/// a layer are closed under walking and then normalized, keeping one state
+/// per region of the man; `layer_states` counts those.
+fn solve_by_pushes<'s>(syl: &'s Sylvan, initial: Bdd<'s>, partitions: Vec<Partition<'s>>, invariant: &Bdd<'s>, goal: Bdd<'s>,
+                   fields: &[Vec<AnnotatedField>], start: u64, limits: &Limits, progress: &mut Progress)
+                   -> Result<Solution, SolveError> {
+  use std::cmp::max;
+
+  let statevars = current_vars(syl, fields);
+  let bddFalse = syl.bddFalse();
+  let (walks, pushes) = partitions.partition(|p| p.kind == Walk);
+  let men = man_vars(fields);
+
+  let mut layer = normalize_man(syl, walks.as_slice(), men.as_slice(), &walk_closure(syl, walks.as_slice(), initial.clone()));
+  let mut reached = layer.clone();
+  let mut layers = vec!(layer.clone());
+
+  let mut stats = Stats::new();
+  stats.largest_layer = reached.node_count();
+  stats.layer_states.push(reached.sat_count(&statevars));
+  while !won(syl, &layer, &goal) {
+    try!(check_limits(start, limits, &mut stats));
+    let region = walk_closure(syl, walks.as_slice(), layer.clone());
+    let pushed = try!(image_within(syl, pushes.as_slice(), &region, start, limits, &mut stats)) & *invariant;
+    let closed = walk_closure(syl, walks.as_slice(), pushed);
+    let next = normalize_man(syl, walks.as_slice(), men.as_slice(), &closed) & !reached;
+    if next == bddFalse {
+      return Err(Unsolvable(stats));
+    }
+    reached = reached | next;
+    stats.iterations += 1;
+    stats.largest_layer = max(stats.largest_layer, max(next.node_count(), reached.node_count()));
+    stats.layer_states.push(next.sat_count(&statevars));
+    report(progress, start, &stats, &next);
+    layers.push(next.clone());
+    layer = next;
   }
+  let path = reconstruct_push_path(syl, layers, initial, goal, walks.as_slice(), pushes.as_slice());
+  stats.millis = (precise_time_ns() - start) / 1000000;
+  Ok(Solution::new(path, stats))
+}
 
-  let equalizer = everything_else_same(fields, &[]);
-  let allvars = all_vars(fields);
-  let mut visited = Vec::new();
+/// Searches backward from every solved position, pulling blocks instead of
+/// pushing them, until the initial position is found. The layers are then
+/// followed forward from the initial position.
+fn solve_backward<'s>(syl: &'s Sylvan, initial: Bdd<'s>, partitions: &[Partition<'s>], fields: &[Vec<AnnotatedField>],
+                  start: u64, limits: &Limits, progress: &mut Progress) -> Result<Solution, SolveError> {
+  use std::cmp::max;
 
-  let mut result = initial;
-  visited.push( result );
+  let statevars = current_vars(syl, fields);
+  let bddFalse = syl.bddFalse();
 
-  println!("Starting");
-  let mut i = 0;
-  loop {
-    let old = result;
-    result = result.relprods_equalize(transitions, allvars, equalizer);
-    result = result | old;
-    visited.push(result);
-    i += 1;
-    if won(result, goal) || old == result {
-      break;
+  let mut frontier = to_bdd_solved(syl, fields);
+  let mut reached = frontier.clone();
+  let mut layers = vec!(frontier.clone());
+
+  let mut stats = Stats::new();
+  stats.largest_layer = frontier.node_count();
+  stats.layer_states.push(frontier.sat_count(&statevars));
+  while !won(syl, &frontier, &initial) {
+    try!(check_limits(start, limits, &mut stats));
+    let previous = try!(preimage_within(syl, partitions, &frontier, start, limits, &mut stats)) & !reached;
+    if previous == bddFalse {
+      return Err(Unsolvable(stats));
     }
+    reached = reached | previous;
+    stats.iterations += 1;
+    stats.largest_layer = max(stats.largest_layer, max(previous.node_count(), reached.node_count()));
+    stats.layer_states.push(previous.sat_count(&statevars));
+    report(progress, start, &stats, &previous);
+    layers.push(previous.clone());
+    frontier = previous;
   }
-  if won(result, goal) {
-    println!("Won in {} steps",i);
-    reconstruct_path(visited, goal, equalizer, fields);
-  } else {
-    println!("Fail in {} steps", i);
-    println!("no solution");
-    panic!()
+
+  let mut current = initial;
+  let mut path = Vec::with_capacity( layers.len() );
+  for i in range(1, layers.len()).rev() {
+    let (partition, next) = step_forward(syl, layers.get(i-1), partitions, &current);
+    path.push_all(partition.moves.as_slice());
+    current = next;
+  }
+  stats.millis = (precise_time_ns() - start) / 1000000;
+  Ok(Solution::new(path, stats))
+}
+
+/// The index of the first of `layers` that shares a state with `set`.
+fn first_meeting<'s>(syl: &'s Sylvan, layers: &[Bdd<'s>], set: &Bdd<'s>) -> uint {
+  let bddFalse = syl.bddFalse();
+  range(0, layers.len()).find(|&i| layers[i] & *set != bddFalse).expect("Layers do not meet")
+}
+
+/// Searches forward from the initial position and backward from the solved
+/// positions at the same time, each round expanding whichever frontier has
+/// the fewest bdd nodes, until the two meet. Because a new layer is checked
+/// against every layer of the other side, and the earliest of those is used,
+/// the solution is as short as the one found by a plain forward search.
+fn solve_bidirectional<'s>(syl: &'s Sylvan, initial: Bdd<'s>, partitions: &[Partition<'s>], invariant: &Bdd<'s>,
+                       fields: &[Vec<AnnotatedField>], start: u64, limits: &Limits, progress: &mut Progress)
+                       -> Result<Solution, SolveError> {
+  use std::cmp::max;
+
+  let statevars = current_vars(syl, fields);
+  let bddFalse = syl.bddFalse();
+
+  let solved = to_bdd_solved(syl, fields);
+  let mut forward = vec!(initial.clone());
+  let mut forward_reached = initial.clone();
+  let mut backward = vec!(solved.clone());
+  let mut backward_reached = solved;
+
+  let mut stats = Stats::new();
+  stats.largest_layer = max(forward_reached.node_count(), backward_reached.node_count());
+
+  // The backward layer in which the meeting states lie; when the sides meet
+  // the forward layers are cut back to end in the meeting states too.
+  let mut meet_backward = 0u;
+  while forward.last().unwrap() & *backward.get(meet_backward) == bddFalse {
+    try!(check_limits(start, limits, &mut stats));
+    if forward.last().unwrap().node_count() <= backward.last().unwrap().node_count() {
+      let next = try!(image_within(syl, partitions, forward.last().unwrap(), start, limits, &mut stats)) & !forward_reached & *invariant;
+      if next == bddFalse {
+        return Err(Unsolvable(stats));
+      }
+      forward_reached = forward_reached | next;
+      stats.largest_layer = max(stats.largest_layer, max(next.node_count(), forward_reached.node_count()));
+      stats.layer_states.push(next.sat_count(&statevars));
+      report(progress, start, &stats, &next);
+      let meets = next & backward_reached != bddFalse;
+      forward.push(next);
+      if meets {
+        meet_backward = first_meeting(syl, backward.as_slice(), forward.last().unwrap());
+      }
+    } else {
+      let previous = try!(preimage_within(syl, partitions, backward.last().unwrap(), start, limits, &mut stats)) & !backward_reached;
+      if previous == bddFalse {
+        return Err(Unsolvable(stats));
+      }
+      backward_reached = backward_reached | previous;
+      stats.largest_layer = max(stats.largest_layer, max(previous.node_count(), backward_reached.node_count()));
+      stats.layer_states.push(previous.sat_count(&statevars));
+      report(progress, start, &stats, &previous);
+      let meets = previous & forward_reached != bddFalse;
+      backward.push(previous);
+      if meets {
+        let meet_forward = first_meeting(syl, forward.as_slice(), backward.last().unwrap());
+        forward.truncate(meet_forward + 1);
+        meet_backward = backward.len() - 1;
+      }
+    }
+    stats.iterations += 1;
+  }
+  let meet_forward = forward.len() - 1;
+
+  // Trace the forward half back from the meeting states, then replay it to
+  // learn which meeting state it actually ends in.
+  let mut current = *forward.get(meet_forward) & *backward.get(meet_backward);
+  let mut steps = Vec::with_capacity( meet_forward );
+  for i in range(1, meet_forward + 1).rev() {
+    let (partition, previous) = step_back(syl, forward.get(i-1), partitions, &current);
+    steps.push(partition);
+    current = previous;
+  }
+  steps.reverse();
+  let mut path: Vec<Move> = Vec::new();
+  current = initial;
+  for partition in steps.iter() {
+    path.push_all(partition.moves.as_slice());
+    current = partition.image(&current);
+  }
+
+  // Follow the backward half from there to a solved position.
+  for j in range(1, meet_backward + 1).rev() {
+    let (partition, next) = step_forward(syl, backward.get(j-1), partitions, &current);
+    path.push_all(partition.moves.as_slice());
+    current = next;
   }
+  stats.millis = (precise_time_ns() - start) / 1000000;
+  Ok(Solution::new(path, stats))
+}
+
+/// Every position from which the puzzle can still be solved, found by
+/// searching backward from the solved positions until nothing new turns up.
+/// The limits are checked before each layer.
+fn solvable_positions<'s>(syl: &'s Sylvan, partitions: &[Partition<'s>], fields: &[Vec<AnnotatedField>],
+                          start: u64, limits: &Limits, stats: &mut Stats) -> Result<Bdd<'s>, SolveError> {
+  use std::cmp::max;
+
+  let bddFalse = syl.bddFalse();
+  let mut frontier = to_bdd_solved(syl, fields);
+  let mut reached = frontier.clone();
+  stats.largest_layer = frontier.node_count();
+  loop {
+    try!(check_limits(start, limits, stats));
+    let previous = try!(preimage_within(syl, partitions, &frontier, start, limits, stats)) & !reached;
+    if previous == bddFalse {
+      return Ok(reached);
+    }
+    reached = reached | previous;
+    stats.iterations += 1;
+    stats.largest_layer = max(stats.largest_layer, max(previous.node_count(), reached.node_count()));
+    frontier = previous;
+  }
+}
+
+/// Counts the positions from which the puzzle can still be solved, within
+/// `limits`.
+pub fn count_solvable(syl: &Sylvan, soko: &SokoAnnotatedBoard, limits: &Limits) -> Result<f64, SolveError> {
+  let start = precise_time_ns();
+  try!(sanityCheck(soko));
+  let fields = soko.board.as_slice();
+  let partitions = to_bdd_partitions(syl, fields, false, false);
+  let mut stats = Stats::new();
+  let solvable = try!(solvable_positions(syl, partitions.as_slice(), fields, start, limits, &mut stats));
+  Ok(solvable.sat_count(&current_vars(syl, fields)))
+}
+
+/// How big the state space of a puzzle is. `reachable` is the number of
+/// positions reachable from the initial position, `solved` how many of
+/// those are solved, and `layer_states` the number of positions first
+/// reached after each number of moves.
+pub struct Analysis {
+  pub reachable: f64,
+  pub solved: f64,
+  pub layer_states: Vec<f64>,
 }
 
-/// Initializes sylvan, our bdd-library
-fn sylvan_init() {
-  unsafe {
-    super::raw::raw_init();
+/// Explores every position reachable from the initial position, one layer of
+/// moves at a time until nothing new turns up, without stopping at a solved
+/// position.
+pub fn analyze(syl: &Sylvan, soko: &SokoAnnotatedBoard, limits: &Limits, progress: &mut Progress) -> Result<Analysis, SolveError> {
+  use std::cmp::max;
+
+  let start = precise_time_ns();
+  try!(sanityCheck(soko));
+  let fields = soko.board.as_slice();
+  let partitions = to_bdd_partitions(syl, fields, false, false);
+  let statevars = current_vars(syl, fields);
+  let bddFalse = syl.bddFalse();
+
+  let mut frontier = to_bdd_init(syl, fields);
+  let mut reached = frontier.clone();
+  let mut stats = Stats::new();
+  stats.largest_layer = frontier.node_count();
+  stats.layer_states.push(frontier.sat_count(&statevars));
+  loop {
+    try!(check_limits(start, limits, &mut stats));
+    let next = try!(image_within(syl, partitions.as_slice(), &frontier, start, limits, &mut stats)) & !reached;
+    if next == bddFalse {
+      break;
+    }
+    reached = reached | next;
+    stats.iterations += 1;
+    stats.largest_layer = max(stats.largest_layer, max(next.node_count(), reached.node_count()));
+    stats.layer_states.push(next.sat_count(&statevars));
+    report(progress, start, &stats, &next);
+    frontier = next;
   }
+  let solved = reached & to_bdd_goal(syl, fields);
+  Ok(Analysis {
+    reachable: reached.sat_count(&statevars),
+    solved: solved.sat_count(&statevars),
+    layer_states: stats.layer_states,
+  })
+}
+
+/// Where the search starts from.
+#[derive(Eq, Clone)]
+pub enum Search {
+  Forward,
+  Backward,
+  Bidirectional,
+}
+
+/// What a solution should minimize.
+#[derive(Eq, Clone)]
+pub enum Mode {
+  MoveOptimal,
+  PushOptimal,
 }
 
-/// Explores the puzzle using sylvan, and prints the solution.
-pub fn do_sylvan(soko: &SokoAnnotatedBoard) {
-  sylvan_init();
+/// Explores the puzzle using the sylvan session `syl`, and returns a solution
+/// that is optimal according to `mode`, searching in the direction given by
+/// `search`. Only forward search supports push-optimal solutions. Every new
+/// layer is reported to `progress`. Positions matching one of the deadlock
+/// `patterns` are dropped from every forward layer; a backward search never
+/// finds them anyway. With `macros` set, pushing a block through a tunnel or
+/// into a goal room counts as a single step, which makes the search faster
+/// but means the solution is no longer guaranteed to be optimal. The time
+/// limit also covers building the transition relation.
+pub fn do_sylvan(syl: &Sylvan, soko: &SokoAnnotatedBoard, patterns: &Patterns, mode: Mode, search: Search,
+                 macros: bool, limits: &Limits, progress: &mut Progress) -> Result<Solution, SolveError> {
+  let start = precise_time_ns();
+  try!(sanityCheck(soko));
   let fields = soko.board.as_slice();
-  let initial = to_bdd_init(fields);
-  let transitions = to_bdd_trans(fields);
-  let goal = to_bdd_goal(fields);
+  let initial = to_bdd_init(syl, fields);
+  let partitions = to_bdd_partitions(syl, fields, true, macros);
+  let invariant = to_bdd_patterns(syl, fields, patterns);
+  if initial & invariant == syl.bddFalse() {
+    return Err(Unsolvable(Stats::new()));
+  }
+  let goal = to_bdd_goal(syl, fields);
   // solve!
-  solve_the_puzzle(initial, transitions, goal, fields);
+  match (mode, search) {
+    (MoveOptimal, Forward) => solve_the_puzzle(syl, initial, partitions.as_slice(), &invariant, goal, fields, start, limits, progress),
+    (MoveOptimal, Backward) => solve_backward(syl, initial, partitions.as_slice(), fields, start, limits, progress),
+    (MoveOptimal, Bidirectional) => solve_bidirectional(syl, initial, partitions.as_slice(), &invariant, fields, start, limits, progress),
+    (PushOptimal, Forward) => solve_by_pushes(syl, initial, partitions, &invariant, goal, fields, start, limits, progress),
+    (PushOptimal, _) => Err(Unsupported(StrBuf::from_str("push-optimal solutions require a forward search"))),
+  }
+}
+
+/// The symbolic search as a `Solver`.
+pub struct SymbolicSolver<'a> {
+  syl: &'a Sylvan,
+  patterns: &'a Patterns,
+  mode: Mode,
+  search: Search,
+  macros: bool,
+}
+
+impl<'a> SymbolicSolver<'a> {
+  pub fn new(syl: &'a Sylvan, patterns: &'a Patterns, mode: Mode, search: Search, macros: bool) -> SymbolicSolver<'a> {
+    SymbolicSolver {
+      syl: syl,
+      patterns: patterns,
+      mode: mode,
+      search: search,
+      macros: macros,
+    }
+  }
+}
+
+impl<'a> Solver for SymbolicSolver<'a> {
+  fn solve_with_progress(&self, board: &SokoAnnotatedBoard, limits: &Limits, progress: &mut Progress)
+      -> Result<Solution, SolveError> {
+    do_sylvan(self.syl, board, self.patterns, self.mode, self.search, self.macros, limits, progress)
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use bdd::{Sylvan, Config};
+  use patterns::{Patterns};
+  use sokoboard::{SokoBoard};
+  use solver::{Solver, Solution, SolveError, Limits};
+  use super::{SokoAnnotatedBoard, SymbolicSolver, Mode, MoveOptimal, PushOptimal};
+  use super::{Search, Forward, Backward, Bidirectional};
+
+  fn annotated(rows: &[&str]) -> SokoAnnotatedBoard {
+    SokoAnnotatedBoard::fromSokoBoard(SokoBoard::parse(rows.connect("\n").as_slice()).unwrap())
+  }
+
+  /// A goal room behind (4,3), with a smaller one behind (7,3) inside it.
+  static NESTED_ROOMS: &'static [&'static str] = &[
+    "#######",
+    "#  @  #",
+    "# $$$ #",
+    "#     #",
+    "###-###",
+    "#     #",
+    "#.   .#",
+    "###-###",
+    "  #.#",
+    "  ###",
+  ];
+
+  #[test]
+  fn nested_goal_rooms_are_merged() {
+    let rooms = annotated(NESTED_ROOMS).goalRooms();
+    assert_eq!(rooms.len(), 1);
+    let room = rooms.get(0);
+    assert_eq!(room.entrance, (4, 3));
+    assert_eq!(room.squares.len(), 12);
+    assert!(room.squares.contains(&(8, 3)));
+  }
+
+  static CORRIDOR: &'static [&'static str] = &[
+    "#######",
+    "#@ $ .#",
+    "#######",
+  ];
+
+  /// Solves `rows` with every combination in `searches`, in a single sylvan
+  /// session.
+  fn solve_all(rows: &[&str], searches: &[(Mode, Search, bool)]) -> Vec<Result<Solution, SolveError>> {
+    let mut config = Config::new();
+    config.workers = 2;
+    config.table_size = 20;
+    config.cache_size = 18;
+    let patterns = Patterns::new();
+    let board = annotated(rows);
+    Sylvan::run(config, |syl| {
+      searches.iter().map(|&(mode, search, macros)| {
+        SymbolicSolver::new(syl, &patterns, mode, search, macros).solve(&board, &Limits::new())
+      }).collect()
+    }).unwrap()
+  }
+
+  /// The plan of `result`, which has to solve `rows` with every push marked.
+  fn checked(rows: &[&str], result: &Result<Solution, SolveError>) -> (uint, uint) {
+    let board = SokoBoard::parse(rows.connect("\n").as_slice()).unwrap();
+    match *result {
+      Ok(ref solution) => {
+        assert!(board.verify_strict(solution.lurd().as_slice()) == Ok(true));
+        (solution.steps, solution.pushes)
+      }
+      Err(ref e) => panic!("{}", e),
+    }
+  }
+
+  #[test]
+  fn every_search_solves_a_corridor() {
+    let searches = [(MoveOptimal, Forward, false), (MoveOptimal, Backward, false),
+                    (MoveOptimal, Bidirectional, false), (PushOptimal, Forward, false)];
+    for result in solve_all(CORRIDOR, searches.as_slice()).iter() {
+      assert_eq!(checked(CORRIDOR, result), (3, 2));
+    }
+  }
+
+  #[test]
+  fn macros_solve_nested_goal_rooms() {
+    let searches = [(MoveOptimal, Forward, true), (PushOptimal, Forward, true), (PushOptimal, Forward, false)];
+    let results = solve_all(NESTED_ROOMS, searches.as_slice());
+    for result in results.iter() {
+      checked(NESTED_ROOMS, result);
+    }
+  }
 }
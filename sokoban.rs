@@ -1,39 +1,431 @@
 #![crate_type = "bin"]
+#![feature(unsafe_destructor)]
 
 #![allow(unused_must_use)]
 
 //extern crate native;
 extern crate libc;
+extern crate getopts;
+extern crate time;
+extern crate collections;
 
-use std::from_str::{FromStr};
 use std::io::{File};
 use std::io::stdio::{stdin};
 use std::path::{Path};
 use std::os;
+use std::strbuf::StrBuf;
 
-use sokoboard::{SokoBoard};
-use sokoannotatedboard::{SokoAnnotatedBoard, do_sylvan};
+use getopts::{optopt, optflag, getopts, usage, OptGroup, Matches};
+
+use bdd::{Sylvan, Config};
+use collection::{Levels};
+use heuristic::{push_lower_bound};
+use explicit::{ExplicitSolver};
+use patterns::{Patterns};
+use solver::{Solver, Limits, Progress, LayerReport, Silent};
+use sokoannotatedboard::{SokoAnnotatedBoard, SymbolicSolver, count_solvable, analyze};
+use sokoannotatedboard::{Mode, MoveOptimal, PushOptimal, Search, Forward, Backward, Bidirectional};
 
 mod raw;
 mod bdd;
 mod sokoboard;
 mod sokoannotatedboard;
+mod collection;
+mod solver;
+mod batch;
+mod grid;
+mod explicit;
+mod heuristic;
+mod patterns;
+mod corral;
+
+/// The solvers to choose from on the command line.
+#[derive(Eq, Clone)]
+enum Backend {
+  Symbolic,
+  Explicit,
+}
+
+/// The settings given on the command line.
+struct Options {
+  help: bool,
+  batch: Option<Path>,
+  csv: Option<Path>,
+  file: Option<Path>,
+  verify: Option<Vec<StrBuf>>,
+  generate: Option<Path>,
+  patterns: Option<Path>,
+  backend: Backend,
+  mode: Mode,
+  search: Search,
+  macros: bool,
+  verbose: bool,
+  analyze: bool,
+  count_solvable: bool,
+  lower_bound: bool,
+  time_limit: Option<u64>,
+  max_iterations: Option<uint>,
+  max_nodes: Option<uint>,
+  config: Config,
+}
+
+fn opts() -> Vec<OptGroup> {
+  vec!(
+    optopt("b", "batch", "solve every level in every file below DIR", "DIR"),
+    optopt("", "csv", "with --batch, also write the report to FILE", "FILE"),
+    optopt("", "patterns", "prune with the deadlock patterns in FILE (default: the built-in patterns.txt)", "FILE"),
+    optopt("", "backend", "solve with 'bdd' (default) or 'explicit' (A* over single positions, push-optimal)", "NAME"),
+    optopt("", "optimize", "find solutions with the fewest 'moves' (default) or 'pushes'", "WHAT"),
+    optopt("", "search", "search 'forward' (default) from the initial position, 'backward' from the solved ones, or 'bidirectional'", "HOW"),
+    optflag("", "macros", "push blocks through tunnels and into goal rooms in one step; solutions may no longer be optimal"),
+    optflag("v", "verbose", "show the progress of the search after every layer"),
+    optflag("", "analyze", "instead of solving, count the positions reachable from the start of each level"),
+    optflag("", "count-solvable", "count the positions from which each level can still be solved"),
+    optflag("", "lower-bound", "print a lower bound on the number of pushes each level needs"),
+    optopt("t", "timeout", "give up on a level after SECS seconds", "SECS"),
+    optopt("", "max-iterations", "give up on a level after N layers (explicit: N positions)", "N"),
+    optopt("", "max-nodes", "give up on a level once a layer needs more than N bdd nodes (explicit: N positions stored)", "N"),
+    optopt("j", "threads", "use N lace workers (default: one per core)", "N"),
+    optopt("", "table-size", "use 2^N entries for sylvan's node table (default 26)", "N"),
+    optopt("", "cache-size", "use 2^N entries for sylvan's operation cache (default 22)", "N"),
+    optopt("", "granularity", "share one operation cache entry between N levels of recursion (default 2)", "N"),
+    optopt("", "deque-size", "give each lace worker a task deque of N entries (default 100000)", "N"),
+    optflag("h", "help", "print this help")
+  )
+}
+
+/// Parses the value of option `name` if it was given.
+fn numeric_opt<T: FromStr>(matches: &Matches, name: &str) -> Result<Option<T>, StrBuf> {
+  match matches.opt_str(name) {
+    Some(value) => match from_str::<T>(value.as_slice()) {
+      Some(n) => Ok(Some(n)),
+      None => Err(StrBuf::from_owned_str(format!("Invalid value for --{}: {}", name, value))),
+    },
+    None => Ok(None),
+  }
+}
+
+fn parse_options(args: &[~str]) -> Result<Options, StrBuf> {
+  let matches = match getopts(args, opts().as_slice()) {
+    Ok(matches) => matches,
+    Err(f) => return Err(StrBuf::from_owned_str(f.to_err_msg())),
+  };
+  let mut config = Config::new();
+  config.workers = try!(numeric_opt(&matches, "threads")).unwrap_or(config.workers);
+  if config.workers == 0 {
+    return Err(StrBuf::from_str("--threads must be at least 1"));
+  }
+  config.table_size = try!(numeric_opt(&matches, "table-size")).unwrap_or(config.table_size);
+  config.cache_size = try!(numeric_opt(&matches, "cache-size")).unwrap_or(config.cache_size);
+  config.granularity = try!(numeric_opt(&matches, "granularity")).unwrap_or(config.granularity);
+  if config.granularity == 0 {
+    return Err(StrBuf::from_str("--granularity must be at least 1"));
+  }
+  config.deque_size = try!(numeric_opt(&matches, "deque-size")).unwrap_or(config.deque_size);
+  if config.deque_size == 0 {
+    return Err(StrBuf::from_str("--deque-size must be at least 1"));
+  }
+  let backend = match matches.opt_str("backend") {
+    None => Symbolic,
+    Some(name) => match name.as_slice() {
+      "bdd" => Symbolic,
+      "explicit" => Explicit,
+      _ => return Err(StrBuf::from_owned_str(format!("Invalid value for --backend: {}", name))),
+    },
+  };
+  let mode = match matches.opt_str("optimize") {
+    None if backend == Explicit => PushOptimal,
+    None => MoveOptimal,
+    Some(what) => match what.as_slice() {
+      "moves" => MoveOptimal,
+      "pushes" => PushOptimal,
+      _ => return Err(StrBuf::from_owned_str(format!("Invalid value for --optimize: {}", what))),
+    },
+  };
+  let search = match matches.opt_str("search") {
+    None => Forward,
+    Some(how) => match how.as_slice() {
+      "forward" => Forward,
+      "backward" => Backward,
+      "bidirectional" => Bidirectional,
+      _ => return Err(StrBuf::from_owned_str(format!("Invalid value for --search: {}", how))),
+    },
+  };
+  if mode == PushOptimal && search != Forward {
+    return Err(StrBuf::from_str("--optimize pushes requires --search forward"));
+  }
+  if backend == Explicit && (mode != PushOptimal || search != Forward) {
+    return Err(StrBuf::from_str("--backend explicit only searches forward for push-optimal solutions"));
+  }
+  if backend == Explicit && matches.opt_present("macros") {
+    return Err(StrBuf::from_str("--macros requires --backend bdd"));
+  }
+  let (file, verify, generate) = match matches.free.as_slice() {
+    [ref command, ref file, ..solutions] if command.as_slice() == "verify" => {
+      (Some(Path::new(file.as_slice())), Some(solutions.iter().map(|s| StrBuf::from_str(s.as_slice())).collect()), None)
+    }
+    [ref command] if command.as_slice() == "verify" => {
+      return Err(StrBuf::from_str("verify needs a FILE, followed by a solution for each level"));
+    }
+    [ref command, ref file] if command.as_slice() == "patterns" => (None, None, Some(Path::new(file.as_slice()))),
+    [ref command, ..] if command.as_slice() == "patterns" => {
+      return Err(StrBuf::from_str("patterns needs the FILE to write the patterns to"));
+    }
+    free => (free.iter().next().map(|file| Path::new(file.as_slice())), None, None),
+  };
+  Ok(Options {
+    help: matches.opt_present("h"),
+    batch: matches.opt_str("batch").map(|dir| Path::new(dir)),
+    csv: matches.opt_str("csv").map(|csv| Path::new(csv)),
+    file: file,
+    verify: verify,
+    generate: generate,
+    patterns: matches.opt_str("patterns").map(|file| Path::new(file)),
+    backend: backend,
+    mode: mode,
+    search: search,
+    macros: matches.opt_present("macros"),
+    verbose: matches.opt_present("v"),
+    analyze: matches.opt_present("analyze"),
+    count_solvable: matches.opt_present("count-solvable"),
+    lower_bound: matches.opt_present("lower-bound"),
+    time_limit: try!(numeric_opt(&matches, "timeout")),
+    max_iterations: try!(numeric_opt(&matches, "max-iterations")),
+    max_nodes: try!(numeric_opt(&matches, "max-nodes")),
+    config: config,
+  })
+}
+
+/// Replays each of `solutions` on the level in `contents` at the same
+/// position, and tells whether they all solve their level.
+fn verify_all(contents: &str, solutions: &[StrBuf]) -> bool {
+  let levels: Vec<_> = Levels::new(contents).collect();
+  if levels.len() != solutions.len() {
+    println!("There are {} levels but {} solutions", levels.len(), solutions.len());
+    return false;
+  }
+  let mut ok = true;
+  for (level, solution) in levels.move_iter().zip(solutions.iter()) {
+    match level {
+      Ok(level) => match level.board.verify(solution.as_slice()) {
+        Ok(true) => println!("{}: solved", level.name()),
+        Ok(false) => {
+          println!("{}: the moves do not solve the level", level.name());
+          ok = false;
+        }
+        Err(e) => {
+          println!("{}: {}", level.name(), e);
+          ok = false;
+        }
+      },
+      Err(e) => {
+        println!("Invalid sokoban board: {}", e);
+        ok = false;
+      }
+    }
+  }
+  ok
+}
+
+/// Prints a line for every layer of the search.
+struct Verbose;
+
+impl Progress for Verbose {
+  fn layer(&mut self, report: &LayerReport) {
+    println!("Layer {}: {} states, {} nodes, {} ms", report.layer, report.states, report.nodes, report.millis);
+  }
+}
 
 fn main() {
   let args = os::args();
-  let contents;
-  if args.len() > 1 {
-    contents = File::open(&Path::new(args[1].as_slice())).read_to_str();
-    println!("Reading from file.");
-  } else {
-    contents = stdin().read_to_str();
-    println!("Reading from stdin.");
+  let options = match parse_options(args.tail()) {
+    Ok(options) => options,
+    Err(msg) => {
+      println!("{}", msg);
+      os::set_exit_status(2);
+      return;
+    }
+  };
+  if options.help {
+    println!("{}", usage(format!("Usage: {0} [options] [FILE]\n       {0} verify FILE SOLUTION...\n       {0} patterns FILE",
+                                 args[0]), opts().as_slice()));
+    return;
+  }
+
+  match (&options.verify, &options.file) {
+    (&Some(ref solutions), &Some(ref file)) => {
+      match File::open(file).read_to_str() {
+        Ok(contents) => {
+          if !verify_all(contents.as_slice(), solutions.as_slice()) {
+            os::set_exit_status(1);
+          }
+        }
+        Err(e) => {
+          println!("Cannot read {}: {}", file.display(), e);
+          os::set_exit_status(2);
+        }
+      }
+      return;
+    }
+    _ => {}
+  }
+
+  match options.generate {
+    Some(ref file) => {
+      let patterns = Patterns::generate();
+      match patterns.save(file) {
+        Ok(()) => println!("Wrote {} patterns to {}", patterns.len(), file.display()),
+        Err(e) => {
+          println!("Cannot write {}: {}", file.display(), e);
+          os::set_exit_status(2);
+        }
+      }
+      return;
+    }
+    None => {}
   }
 
-  let board: SokoBoard = FromStr::from_str( contents.unwrap() )
-            .expect("Invalid sokoban board");
+  let loaded = match options.patterns {
+    Some(ref file) => Patterns::load(file),
+    None => Patterns::builtin(),
+  };
+  let patterns = match loaded {
+    Ok(patterns) => patterns,
+    Err(msg) => {
+      println!("{}", msg);
+      os::set_exit_status(2);
+      return;
+    }
+  };
 
-  let annotated = SokoAnnotatedBoard::fromSokoBoard(board);
-  do_sylvan(&annotated);
+  let ran = Sylvan::run(options.config.clone(), |syl| solve_levels(&options, &patterns, syl));
+  if ran.is_none() {
+    println!("sylvan is already running");
+    os::set_exit_status(2);
+  }
 }
 
+/// Solves, counts or analyzes every level the options ask for. Runs inside
+/// the sylvan session, as lace's root task.
+fn solve_levels(options: &Options, patterns: &Patterns, syl: &Sylvan) {
+  let symbolic = SymbolicSolver::new(syl, patterns, options.mode, options.search, options.macros);
+  let explicit = ExplicitSolver::new(patterns);
+  let solver = match options.backend {
+    Symbolic => &symbolic as &Solver,
+    Explicit => &explicit as &Solver,
+  };
+  let limits = Limits {
+    time: options.time_limit,
+    iterations: options.max_iterations,
+    nodes: options.max_nodes,
+    cancel: None,
+  };
+
+  match options.batch {
+    Some(ref dir) => {
+      batch::run(dir, solver, &limits, options.csv.clone());
+      return;
+    }
+    None => {}
+  }
+
+  let contents;
+  match options.file {
+    Some(ref file) => {
+      contents = File::open(file).read_to_str();
+      println!("Reading from file.");
+    }
+    None => {
+      contents = stdin().read_to_str();
+      println!("Reading from stdin.");
+    }
+  }
+
+  let contents = contents.unwrap();
+  let mut failed = false;
+  for level in Levels::new( contents.as_slice() ) {
+    match level {
+      Ok(level) => {
+        println!("{}", level.name());
+        let annotated = SokoAnnotatedBoard::fromSokoBoard(level.board.clone());
+        if options.count_solvable {
+          match count_solvable(syl, &annotated, &limits) {
+            Ok(n) => println!("Solvable positions: {}", n),
+            Err(e) => println!("{}", e),
+          }
+        }
+        if options.lower_bound {
+          match push_lower_bound(&annotated) {
+            Some(n) => println!("At least {} pushes", n),
+            None => println!("Some block cannot reach a goal"),
+          }
+        }
+        if options.analyze {
+          let analysis = if options.verbose {
+            analyze(syl, &annotated, &limits, &mut Verbose)
+          } else {
+            analyze(syl, &annotated, &limits, &mut Silent)
+          };
+          match analysis {
+            Ok(analysis) => {
+              println!("Reachable positions: {}", analysis.reachable);
+              println!("Reachable solved positions: {}", analysis.solved);
+              println!("Tunnels: {}, goal rooms: {}", annotated.tunnels().len(), annotated.goalRooms().len());
+              print!("States per layer:");
+              for n in analysis.layer_states.iter() {
+                print!(" {}", n);
+              }
+              println!("");
+            }
+            Err(e) => {
+              println!("{}", e);
+              failed = true;
+            }
+          }
+          continue;
+        }
+        let result = if options.verbose {
+          solver.solve_with_progress(&annotated, &limits, &mut Verbose)
+        } else {
+          solver.solve(&annotated, &limits)
+        };
+        match result {
+          Ok(solution) => {
+            println!("Won in {} moves, {} pushes", solution.steps, solution.pushes);
+            if solution.stats.corrals > 0 {
+              println!("Corrals found in {} positions, {} pushes pruned", solution.stats.corrals, solution.stats.pruned);
+            }
+            print!("States per layer:");
+            for n in solution.stats.layer_states.iter() {
+              print!(" {}", n);
+            }
+            println!("");
+            println!("Solution: {}", solution.lurd());
+            match level.board.verify_strict(solution.lurd().as_slice()) {
+              Ok(true) => {}
+              Ok(false) => {
+                println!("The solution does not solve the level");
+                failed = true;
+              }
+              Err(e) => {
+                println!("The solution is not valid: {}", e);
+                failed = true;
+              }
+            }
+          }
+          Err(e) => {
+            println!("{}", e);
+            failed = true;
+          }
+        }
+      }
+      Err(e) => {
+        println!("Invalid sokoban board: {}", e);
+        failed = true;
+      }
+    }
+  }
+  if failed {
+    os::set_exit_status(1);
+  }
+}
@@ -1,32 +1,307 @@
 use std::fmt;
 use std::from_str::{FromStr};
 
+#[derive(Clone)]
 pub struct SokoBoard( pub Vec<Vec<Field>> );
 
-impl FromStr for SokoBoard {
-  fn from_str(s: &str) -> Option<SokoBoard> {
+/// The most squares a row of a board may have.
+pub static MAX_WIDTH: uint = 1024;
+
+/// The reason a board could not be parsed.
+#[derive(Eq, Clone)]
+pub enum ParseErrorReason {
+  UnknownGlyph,
+  EmptyBoard,
+  NoMan,
+  MultipleMen,
+  DanglingRunLength,
+  ZeroRunLength,
+  TooWide,
+}
+
+/// Describes why and where parsing a board failed. `line` and `column` are
+/// 1-based; they are 0 when the error does not concern a particular square
+/// (an empty board, or a board without a man).
+#[derive(Eq, Clone)]
+pub struct ParseError {
+  pub line: uint,
+  pub column: uint,
+  pub character: Option<char>,
+  pub reason: ParseErrorReason,
+}
+
+impl ParseError {
+  fn new(line: uint, column: uint, character: Option<char>, reason: ParseErrorReason) -> ParseError {
+    ParseError {
+      line: line,
+      column: column,
+      character: character,
+      reason: reason,
+    }
+  }
+}
+
+impl fmt::Show for ParseError {
+  fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+    let reason = match self.reason {
+      UnknownGlyph => "unknown glyph",
+      EmptyBoard => "empty board",
+      NoMan => "no man on the board",
+      MultipleMen => "more than one man on the board",
+      DanglingRunLength => "run length without a glyph",
+      ZeroRunLength => "run length of zero",
+      TooWide => "row wider than the widest board allowed",
+    };
+    match self.character {
+      Some(c) => write!(fmt.buf, "{} {:?} at line {}, column {}", reason, c, self.line, self.column),
+      None if self.line > 0 => write!(fmt.buf, "{} at line {}, column {}", reason, self.line, self.column),
+      None => write!(fmt.buf, "{}", reason),
+    }
+  }
+}
+
+impl SokoBoard {
+  /// Parses a board, reporting the position of the first problem found.
+  /// Besides the canonical XSB glyphs this accepts `-` and `_` for floor,
+  /// `p`/`P` for the man, `b`/`B` for blocks, and run-length encoded rows
+  /// (`4#3-$`) where `|` separates rows. A trailing carriage return on a
+  /// line is ignored, and so is a `|` at the end of a line. A run length
+  /// may not be zero, and no row may be wider than `MAX_WIDTH`.
+  pub fn parse(s: &str) -> Result<SokoBoard, ParseError> {
     use std::cmp::max;
-    let lines: Vec<&str> = s.lines().collect();
-    let rows = lines.len();
-    let cols = lines.iter().fold(0usize, |maxL, row| max(maxL,row.len()));
-    if rows == 0 || cols == 0 {
-      None
-    } else {
-      let mut result = Vec::new();
-      for line in lines.iter() {
-        let mut row = Vec::new();
-        for ix in range(0,line.len()) {
-          let field: &str = line.slice_chars(ix, ix+1);
-          let fld: Field = FromStr::from_str(field).expect(format!("Invalid character {:?} in screen!", field));
+    let mut man = false;
+    let mut result = Vec::new();
+    for (lineIx, line) in s.lines_any().enumerate() {
+      let mut row = Vec::new();
+      let mut count: Option<uint> = None;
+      let mut colNo = 0u;
+      let mut split = false;
+      for (colIx, chr) in line.chars().enumerate() {
+        colNo = colIx+1;
+        if chr.is_digit() {
+          let n = count.unwrap_or(0) * 10 + chr.to_digit(10).unwrap();
+          if n > MAX_WIDTH {
+            return Err(ParseError::new(lineIx+1, colNo, Some(chr), TooWide));
+          }
+          count = Some(n);
+          continue;
+        }
+        if chr == '|' {
+          if count.is_some() {
+            return Err(ParseError::new(lineIx+1, colNo, Some(chr), DanglingRunLength));
+          }
+          result.push(row);
+          row = Vec::new();
+          split = true;
+          continue;
+        }
+        let fld = match Field::from_char(chr) {
+          Some(fld) => fld,
+          None => return Err(ParseError::new(lineIx+1, colNo, Some(chr), UnknownGlyph)),
+        };
+        let repeat = match count {
+          Some(0) => return Err(ParseError::new(lineIx+1, colNo, Some(chr), ZeroRunLength)),
+          Some(n) => n,
+          None => 1,
+        };
+        count = None;
+        if row.len() + repeat > MAX_WIDTH {
+          return Err(ParseError::new(lineIx+1, colNo, Some(chr), TooWide));
+        }
+        if fld == Man || fld == ManOnGoal {
+          if man || repeat > 1 {
+            return Err(ParseError::new(lineIx+1, colNo, Some(chr), MultipleMen));
+          }
+          man = true;
+        }
+        for _ in range(0, repeat) {
           row.push(fld);
         }
-        while row.len() < cols {
-          row.push(Empty);
+      }
+      if count.is_some() {
+        return Err(ParseError::new(lineIx+1, colNo, None, DanglingRunLength));
+      }
+      if !(split && row.is_empty()) {
+        result.push(row);
+      }
+    }
+
+    let cols = result.iter().fold(0u, |maxL, row| max(maxL,row.len()));
+    if result.len() == 0 || cols == 0 {
+      return Err(ParseError::new(0, 0, None, EmptyBoard));
+    }
+    if !man {
+      return Err(ParseError::new(0, 0, None, NoMan));
+    }
+    for row in result.mut_iter() {
+      while row.len() < cols {
+        row.push(Empty);
+      }
+    }
+    Ok(SokoBoard(result))
+  }
+}
+
+/// The reason a move could not be made.
+#[derive(Eq, Clone)]
+pub enum MoveErrorReason {
+  UnknownMove,
+  Blocked,
+  BlockStuck,
+  NotAPush,
+  UnmarkedPush,
+}
+
+/// Describes which move of a solution could not be made, and why. `index`
+/// is the 0-based position of the move in the solution.
+#[derive(Eq, Clone)]
+pub struct MoveError {
+  pub index: uint,
+  pub character: char,
+  pub reason: MoveErrorReason,
+}
+
+impl fmt::Show for MoveError {
+  fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+    let reason = match self.reason {
+      UnknownMove => "not a move",
+      Blocked => "the man walks into a wall or off the board",
+      BlockStuck => "the block cannot be pushed",
+      NotAPush => "a push that does not push a block",
+      UnmarkedPush => "a push written in lowercase",
+    };
+    write!(fmt.buf, "move {} ({}): {}", self.index + 1, self.character, reason)
+  }
+}
+
+impl SokoBoard {
+  /// Where the man is, as (row, column).
+  fn man(&self) -> (uint, uint) {
+    let &SokoBoard(ref rows) = self;
+    for (row, fields) in rows.iter().enumerate() {
+      for (col, field) in fields.iter().enumerate() {
+        if *field == Man || *field == ManOnGoal {
+          return (row, col);
         }
-        result.push(row.move_iter().collect::<Vec<_>>());
       }
-      Some(SokoBoard(result.move_iter().collect()))
     }
+    panic!("No man on the board");
+  }
+
+  /// The square next to (`row`,`col`) in direction `dir`, if it is on the
+  /// board.
+  fn neighbour(&self, (row, col): (uint, uint), dir: Direction) -> Option<(uint, uint)> {
+    let &SokoBoard(ref rows) = self;
+    let (row, col) = match dir {
+      Up if row > 0 => (row - 1, col),
+      Down => (row + 1, col),
+      Left if col > 0 => (row, col - 1),
+      Right => (row, col + 1),
+      _ => return None,
+    };
+    if row < rows.len() && col < rows[row].len() {
+      Some((row, col))
+    } else {
+      None
+    }
+  }
+
+  fn get(&self, (row, col): (uint, uint)) -> Field {
+    let &SokoBoard(ref rows) = self;
+    rows[row][col]
+  }
+
+  fn set(&mut self, (row, col): (uint, uint), field: Field) {
+    let &SokoBoard(ref mut rows) = self;
+    *rows.get_mut(row).get_mut(col) = field;
+  }
+
+  /// Moves the man one square in direction `dir`, pushing the block in his
+  /// way if there is one. Returns whether a block was pushed; the board is
+  /// left alone when the move cannot be made.
+  pub fn step(&mut self, dir: Direction) -> Result<bool, MoveErrorReason> {
+    let man = self.man();
+    let there = match self.neighbour(man, dir) {
+      Some(there) if self.get(there) != Wall => there,
+      _ => return Err(Blocked),
+    };
+    let pushed = self.get(there).hasBlock();
+    if pushed {
+      let beyond = match self.neighbour(there, dir) {
+        Some(beyond) if self.get(beyond).isFree() => beyond,
+        _ => return Err(BlockStuck),
+      };
+      let field = self.get(beyond);
+      self.set(beyond, if field.isGoal() { BlockOnGoal } else { Block });
+    }
+    let field = self.get(there);
+    self.set(there, if field.isGoal() { ManOnGoal } else { Man });
+    let field = self.get(man);
+    self.set(man, if field.isGoal() { Goal } else { Empty });
+    Ok(pushed)
+  }
+
+  /// Makes the moves in `moves`, written in LURD notation. An uppercase move
+  /// has to push a block; a lowercase one may push a block too. Whitespace
+  /// is skipped. Stops at the first move that cannot be made.
+  pub fn play(&mut self, moves: &str) -> Result<(), MoveError> {
+    self.play_moves(moves, false)
+  }
+
+  /// Like `play`, but every push has to be written in uppercase.
+  pub fn play_strict(&mut self, moves: &str) -> Result<(), MoveError> {
+    self.play_moves(moves, true)
+  }
+
+  fn play_moves(&mut self, moves: &str, strict: bool) -> Result<(), MoveError> {
+    for (index, chr) in moves.chars().enumerate() {
+      if chr.is_whitespace() {
+        continue;
+      }
+      let error = |reason| MoveError { index: index, character: chr, reason: reason };
+      let dir = match chr.to_lowercase() {
+        'u' => Up,
+        'd' => Down,
+        'l' => Left,
+        'r' => Right,
+        _ => return Err(error(UnknownMove)),
+      };
+      match self.step(dir) {
+        Ok(false) if chr.is_uppercase() => return Err(error(NotAPush)),
+        Ok(true) if strict && chr.is_lowercase() => return Err(error(UnmarkedPush)),
+        Ok(_) => {}
+        Err(reason) => return Err(error(reason)),
+      }
+    }
+    Ok(())
+  }
+
+  /// `true` if every block is on a goal.
+  pub fn isSolved(&self) -> bool {
+    let &SokoBoard(ref rows) = self;
+    rows.iter().all(|row| row.iter().all(|field| *field != Block))
+  }
+
+  /// Replays `moves` on a copy of the board, and tells whether they solve
+  /// it.
+  pub fn verify(&self, moves: &str) -> Result<bool, MoveError> {
+    let mut board = self.clone();
+    try!(board.play(moves));
+    Ok(board.isSolved())
+  }
+
+  /// Like `verify`, but replays `moves` with `play_strict`. Used to check
+  /// the solver's own solutions, which always mark their pushes.
+  pub fn verify_strict(&self, moves: &str) -> Result<bool, MoveError> {
+    let mut board = self.clone();
+    try!(board.play_strict(moves));
+    Ok(board.isSolved())
+  }
+}
+
+impl FromStr for SokoBoard {
+  fn from_str(s: &str) -> Option<SokoBoard> {
+    SokoBoard::parse(s).ok()
   }
 }
 
@@ -43,7 +318,7 @@ impl fmt::Show for SokoBoard {
   }
 }
 
-#[derive(Eq)]
+#[derive(Eq, Clone)]
 pub enum Field {
   Empty,
   Wall,
@@ -54,21 +329,47 @@ pub enum Field {
   ManOnGoal,
 }
 
+impl Field {
+  /// Returns the field represented by `c`, if any. Both the canonical XSB
+  /// glyphs and their common alternatives are recognized.
+  pub fn from_char(c: char) -> Option<Field> {
+    match c {
+      ' ' | '-' | '_' => Some(Empty),
+      '#' => Some(Wall),
+      '@' | 'p' => Some(Man),
+      '$' | 'b' => Some(Block),
+      '.' => Some(Goal),
+      '*' | 'B' => Some(BlockOnGoal),
+      '+' | 'P' => Some(ManOnGoal),
+      _   => None,
+    }
+  }
+
+  fn hasBlock(&self) -> bool {
+    *self == Block || *self == BlockOnGoal
+  }
+
+  fn isGoal(&self) -> bool {
+    *self == Goal || *self == BlockOnGoal || *self == ManOnGoal
+  }
+
+  /// `true` if a block can be pushed onto this square.
+  fn isFree(&self) -> bool {
+    *self == Empty || *self == Goal
+  }
+}
+
 impl FromStr for Field {
   fn from_str(s: &str) -> Option<Field> {
-    match s {
-      " " => Some(Empty),
-      "#" => Some(Wall),
-      "@" => Some(Man),
-      "$" => Some(Block),
-      "." => Some(Goal),
-      "*" => Some(BlockOnGoal),
-      "+" => Some(ManOnGoal),
-      _   => None,
+    if s.char_len() == 1 {
+      Field::from_char(s.char_at(0))
+    } else {
+      None
     }
   }
 }
 
+/// Always writes the canonical XSB glyph.
 impl fmt::Show for Field {
   fn fmt(&self, fmt: &mut fmt::Formatter) -> super::std::fmt::Result {
     let chr = match *self {
@@ -84,3 +385,132 @@ impl fmt::Show for Field {
     Ok(())
   }
 }
+
+/// A direction the man can move in.
+#[derive(Eq, Clone)]
+pub enum Direction {
+  Up,
+  Down,
+  Left,
+  Right,
+}
+
+impl Direction {
+  pub fn opposite(&self) -> Direction {
+    match *self {
+      Up => Down,
+      Down => Up,
+      Left => Right,
+      Right => Left,
+    }
+  }
+}
+
+/// A single move of the man, and whether it pushes a block.
+#[derive(Eq, Clone)]
+pub struct Move {
+  pub direction: Direction,
+  pub push: bool,
+}
+
+/// Writes the move in LURD notation, in uppercase for a push.
+impl fmt::Show for Move {
+  fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+    let chr = match self.direction {
+      Up => 'u',
+      Down => 'd',
+      Left => 'l',
+      Right => 'r',
+    };
+    fmt.buf.write_char(if self.push { chr.to_uppercase() } else { chr });
+    Ok(())
+  }
+}
+
+/// Writes the direction in LURD notation.
+impl fmt::Show for Direction {
+  fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+    let chr = match *self {
+      Up => 'u',
+      Down => 'd',
+      Left => 'l',
+      Right => 'r',
+    };
+    fmt.buf.write_char(chr);
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use std::strbuf::StrBuf;
+  use super::{SokoBoard, ParseErrorReason, MoveErrorReason, MAX_WIDTH};
+  use super::{UnknownGlyph, EmptyBoard, NoMan, MultipleMen, DanglingRunLength, ZeroRunLength, TooWide};
+  use super::{UnknownMove, Blocked, BlockStuck, NotAPush, UnmarkedPush};
+
+  fn parse_error(s: &str) -> (ParseErrorReason, uint, uint) {
+    match SokoBoard::parse(s) {
+      Ok(_) => panic!("{} should not parse", s),
+      Err(e) => (e.reason, e.line, e.column),
+    }
+  }
+
+  fn shown(s: &str) -> StrBuf {
+    StrBuf::from_owned_str(format!("{}", SokoBoard::parse(s).unwrap()))
+  }
+
+  fn move_error(board: &str, moves: &str, strict: bool) -> (MoveErrorReason, uint) {
+    let board = SokoBoard::parse(board).unwrap();
+    let result = if strict { board.verify_strict(moves) } else { board.verify(moves) };
+    match result {
+      Ok(solved) => panic!("{} should not be playable (solved: {})", moves, solved),
+      Err(e) => (e.reason, e.index),
+    }
+  }
+
+  static CORRIDOR: &'static str = "######\n#@ $.#\n######";
+
+  #[test]
+  fn every_parse_error_is_located() {
+    assert!(parse_error("#@x#") == (UnknownGlyph, 1, 3));
+    assert!(parse_error("\n\n") == (EmptyBoard, 0, 0));
+    assert!(parse_error("#$.#") == (NoMan, 0, 0));
+    assert!(parse_error("#@\n#@#") == (MultipleMen, 2, 2));
+    assert!(parse_error("#3@#") == (MultipleMen, 1, 3));
+    assert!(parse_error("#@3") == (DanglingRunLength, 1, 3));
+    assert!(parse_error("#@3|#") == (DanglingRunLength, 1, 4));
+    assert!(parse_error("#@0#") == (ZeroRunLength, 1, 4));
+    assert!(parse_error("@1025#") == (TooWide, 1, 5));
+    assert_eq!(MAX_WIDTH, 1024);
+    assert!(parse_error("@1024#") == (TooWide, 1, 6));
+  }
+
+  #[test]
+  fn run_lengths_and_row_separators() {
+    let plain = shown("######\n#@ $.#\n######");
+    assert_eq!(shown("6#|#@-$.#|6#"), plain);
+    assert_eq!(shown("6#\n#p_b.#\n6#|"), plain);
+    assert_eq!(shown("6#\r\n#@1-$.#\r\n3#3#"), plain);
+    assert_eq!(shown("4#\n#@$.#"), shown("####\n#@$.#"));
+  }
+
+  #[test]
+  fn verify_accepts_solutions() {
+    let board = SokoBoard::parse(CORRIDOR).unwrap();
+    assert!(board.verify("rR") == Ok(true));
+    assert!(board.verify("rr") == Ok(true));
+    assert!(board.verify(" r R ") == Ok(true));
+    assert!(board.verify_strict("rR") == Ok(true));
+    assert!(board.verify("r") == Ok(false));
+    assert!(board.verify("") == Ok(false));
+  }
+
+  #[test]
+  fn verify_rejects_bad_moves() {
+    assert!(move_error(CORRIDOR, "x", false) == (UnknownMove, 0));
+    assert!(move_error(CORRIDOR, "u", false) == (Blocked, 0));
+    assert!(move_error(CORRIDOR, "rRR", false) == (BlockStuck, 2));
+    assert!(move_error(CORRIDOR, "R", false) == (NotAPush, 0));
+    assert!(move_error(CORRIDOR, "rr", true) == (UnmarkedPush, 1));
+  }
+}
@@ -0,0 +1,187 @@
+use std::fmt;
+use std::strbuf::StrBuf;
+use std::sync::atomics::{AtomicBool, SeqCst};
+use time::{precise_time_ns};
+
+use super::sokoannotatedboard::{SokoAnnotatedBoard};
+use super::sokoboard::{Move};
+
+/// Statistics gathered while exploring a puzzle.
+/// `iterations` is the number of breadth-first layers computed
+/// `largest_layer` is the size in bdd nodes of the largest layer bdd, which
+/// is not the number of nodes sylvan has in use
+/// `millis` is the wall-clock time spent, in milliseconds
+/// `layer_states` is the number of new states found in each layer
+/// `corrals` is the number of positions where a corral limited the pushes
+/// `pruned` is the number of pushes left out because of those corrals
+#[derive(Clone)]
+pub struct Stats {
+  pub iterations: uint,
+  pub largest_layer: uint,
+  pub millis: u64,
+  pub layer_states: Vec<f64>,
+  pub corrals: uint,
+  pub pruned: uint,
+}
+
+impl Stats {
+  pub fn new() -> Stats {
+    Stats {
+      iterations: 0,
+      largest_layer: 0,
+      millis: 0,
+      layer_states: Vec::new(),
+      corrals: 0,
+      pruned: 0,
+    }
+  }
+}
+
+/// A solved puzzle: the moves of the man, how many there are and how many of
+/// them push a block, and how much work it took to find them.
+pub struct Solution {
+  pub moves: Vec<Move>,
+  pub steps: uint,
+  pub pushes: uint,
+  pub stats: Stats,
+}
+
+impl Solution {
+  pub fn new(moves: Vec<Move>, stats: Stats) -> Solution {
+    Solution {
+      steps: moves.len(),
+      pushes: moves.iter().filter(|m| m.push).count(),
+      moves: moves,
+      stats: stats,
+    }
+  }
+
+  /// The solution in LURD notation, with pushes in uppercase.
+  pub fn lurd(&self) -> StrBuf {
+    let mut sb = StrBuf::with_capacity(self.moves.len());
+    for m in self.moves.iter() {
+      sb.push_str(m.to_str().as_slice());
+    }
+    sb
+  }
+}
+
+/// Why a puzzle was not solved. Where the search got under way, the
+/// statistics up to the point it stopped are included.
+pub enum SolveError {
+  Unsolvable(Stats),
+  InvalidBoard(StrBuf),
+  Unsupported(StrBuf),
+  LimitReached(Limit, Stats),
+  Cancelled(Stats),
+}
+
+impl fmt::Show for SolveError {
+  fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      Unsolvable(ref stats) => write!(fmt.buf, "no solution (searched {} steps)", stats.iterations),
+      InvalidBoard(ref reason) => write!(fmt.buf, "invalid board: {}", reason),
+      Unsupported(ref reason) => write!(fmt.buf, "unsupported search: {}", reason),
+      LimitReached(limit, ref stats) => write!(fmt.buf, "gave up after {} steps: {}", stats.iterations, limit),
+      Cancelled(ref stats) => write!(fmt.buf, "cancelled after {} steps", stats.iterations),
+    }
+  }
+}
+
+/// Which of the `Limits` a search ran into.
+#[derive(Eq, Clone)]
+pub enum Limit {
+  TimeLimit,
+  IterationLimit,
+  NodeLimit,
+}
+
+impl fmt::Show for Limit {
+  fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      TimeLimit => write!(fmt.buf, "time limit reached"),
+      IterationLimit => write!(fmt.buf, "iteration limit reached"),
+      NodeLimit => write!(fmt.buf, "node limit reached"),
+    }
+  }
+}
+
+/// When a search has to give up: after `time` seconds, after `iterations`
+/// steps, once `largest_layer` exceeds `nodes`, or as soon as it notices
+/// `cancel` has been set. What a step and a node are is up to the search, as
+/// for `Stats`.
+pub struct Limits<'a> {
+  pub time: Option<u64>,
+  pub iterations: Option<uint>,
+  pub nodes: Option<uint>,
+  pub cancel: Option<&'a AtomicBool>,
+}
+
+impl<'a> Limits<'a> {
+  /// No limits at all.
+  pub fn new() -> Limits<'a> {
+    Limits {
+      time: None,
+      iterations: None,
+      nodes: None,
+      cancel: None,
+    }
+  }
+}
+
+/// Updates the time spent in `stats`, which started at `start`, and fails
+/// when the search has to stop. Meant to be called before every step, so
+/// the statistics returned are those of the steps completed so far.
+pub fn check_limits(start: u64, limits: &Limits, stats: &mut Stats) -> Result<(), SolveError> {
+  let now = precise_time_ns();
+  stats.millis = (now - start) / 1000000;
+  if limits.cancel.map_or(false, |cancel| cancel.load(SeqCst)) {
+    return Err(Cancelled(stats.clone()));
+  }
+  if limits.time.map_or(false, |secs| now - start > secs * 1000000000) {
+    return Err(LimitReached(TimeLimit, stats.clone()));
+  }
+  if limits.iterations.map_or(false, |iterations| stats.iterations >= iterations) {
+    return Err(LimitReached(IterationLimit, stats.clone()));
+  }
+  if limits.nodes.map_or(false, |nodes| stats.largest_layer > nodes) {
+    return Err(LimitReached(NodeLimit, stats.clone()));
+  }
+  Ok(())
+}
+
+/// What a search has to say after finishing a layer. `layer` is its index,
+/// `states` and `nodes` are its size in states and in nodes, and `millis` is
+/// the time spent so far.
+pub struct LayerReport {
+  pub layer: uint,
+  pub states: f64,
+  pub nodes: uint,
+  pub millis: u64,
+}
+
+/// Watches a search as it goes.
+pub trait Progress {
+  fn layer(&mut self, report: &LayerReport);
+}
+
+/// Progress that is not shown anywhere.
+pub struct Silent;
+
+impl Progress for Silent {
+  fn layer(&mut self, _report: &LayerReport) {}
+}
+
+/// A way of solving puzzles. The command line and the batch runner only see
+/// solvers through this trait, so any of them can be used in their place.
+pub trait Solver {
+  /// Finds a solution for `board`, giving up when `limits` say so, and tells
+  /// `progress` about every layer of the search.
+  fn solve_with_progress(&self, board: &SokoAnnotatedBoard, limits: &Limits, progress: &mut Progress)
+      -> Result<Solution, SolveError>;
+
+  /// Finds a solution for `board`, giving up when `limits` say so.
+  fn solve(&self, board: &SokoAnnotatedBoard, limits: &Limits) -> Result<Solution, SolveError> {
+    self.solve_with_progress(board, limits, &mut Silent)
+  }
+}